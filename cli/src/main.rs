@@ -1,9 +1,11 @@
 use structopt::StructOpt;
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::io::{self, Write};
 use uuid::Uuid;
 use walkdir::WalkDir;
 
-use libcommonplace::{open_db, init_memex, add_note, update_note, rename_note, create_tag, delete_tag, tag_note, untag_note, get_tag_tree, CommonplaceError, Connection};
+use libcommonplace::{open_db, open_db_unmigrated, find_repo, new_repo_path, pending_migrations, migrate_db, init_memex, add_note, update_note, rename_note, delete_note, gc, vacuum, export_bundle, import_bundle, get_peers, forget_peer, list_conflicts, get_conflict, resolve_conflict, Resolution, check_repository, repair_repository, create_tag, delete_tag, rename_tag, move_tag, merge_tags, remove_redundant_tags, get_tag_id_by_name, tag_note, untag_note, get_tag_tree, set_tag_hidden, archive_note, unarchive_note, get_all_notes, parse_query, query_notes, search_notes, get_note_contents, get_note_history, get_previous_contents, get_note_version_contents, restore_note_version, get_note, diff_blobs, get_operation_log, Operation, TagForest, TagId, Note, add_link, delete_link, get_links, get_backlinks, ANNOTATION_LINK, NewLink, LinkTarget, Anchor, get_cards_for_note, get_due_cards, review_card, get_orphaned_cards, get_changed_cards, delete_card, Card, Grade, NoteId, NoteHistoryEntry, CommonplaceError, Connection};
 
 #[derive(Debug)]
 struct TagList(Vec<String>);
@@ -16,13 +18,34 @@ fn parse_taglist(s: &str) -> TagList {
     TagList(out)
 }
 
+// Anything that isn't a note UUID is taken to be a URL.
+fn parse_link_target(s: &str) -> LinkTarget {
+    match s.parse() {
+        Ok(uuid) => LinkTarget::Note(NoteId { uuid }),
+        Err(_) => LinkTarget::Url(s.to_string()),
+    }
+}
+
+#[derive(StructOpt)]
+struct Args {
+    /// A repository name from the config file, or a path to a repository
+    #[structopt(long, global = true)]
+    repo: Option<String>,
+    #[structopt(subcommand)]
+    cmdline: Cmdline,
+}
+
 #[derive(StructOpt)]
 enum Cmdline {
     Init {
         #[structopt(parse(from_os_str))]
         directory: Option<PathBuf>,
     },
-    ShowTree {},
+    ShowTree {
+        /// Include archived notes and other hidden tags
+        #[structopt(long)]
+        include_hidden: bool,
+    },
     AddNote {
         name: String,
         #[structopt(parse(from_os_str))]
@@ -37,6 +60,17 @@ enum Cmdline {
         note: Uuid,
         name: String,
     },
+    DeleteNote {
+        note: Uuid,
+    },
+    /// Hides a note from the standard views by tagging it "archive"
+    Archive {
+        note: Uuid,
+    },
+    /// Removes all hidden tags from a note
+    Unarchive {
+        note: Uuid,
+    },
     CreateTag {
         #[structopt(parse(from_str = parse_taglist))]
         tag: TagList,
@@ -45,6 +79,39 @@ enum Cmdline {
         #[structopt(parse(from_str = parse_taglist))]
         tag: TagList,
     },
+    RenameTag {
+        #[structopt(parse(from_str = parse_taglist))]
+        tag: TagList,
+        name: String,
+    },
+    /// Moves a tag under a new parent, or to the root if no parent is given
+    MoveTag {
+        #[structopt(parse(from_str = parse_taglist))]
+        tag: TagList,
+        #[structopt(parse(from_str = parse_taglist))]
+        parent: Option<TagList>,
+    },
+    /// Moves all notes and children of src to dst, and then deletes src
+    MergeTag {
+        #[structopt(parse(from_str = parse_taglist))]
+        src: TagList,
+        #[structopt(parse(from_str = parse_taglist))]
+        dst: TagList,
+    },
+    /// Untags notes from tags that they also have a more specific descendant of
+    CanonicalizeTags {
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Hides notes with this tag from the standard views
+    HideTag {
+        #[structopt(parse(from_str = parse_taglist))]
+        tag: TagList,
+    },
+    UnhideTag {
+        #[structopt(parse(from_str = parse_taglist))]
+        tag: TagList,
+    },
     TagNote {
         note: Uuid,
         #[structopt(parse(from_str = parse_taglist))]
@@ -55,6 +122,248 @@ enum Cmdline {
         #[structopt(parse(from_str = parse_taglist))]
         tag: TagList,
     },
+    Query {
+        /// Include archived notes and notes with other hidden tags
+        #[structopt(long)]
+        include_hidden: bool,
+        query: Vec<String>,
+    },
+    Search {
+        query: Vec<String>,
+    },
+    History {
+        note: Uuid,
+    },
+    /// Prints a version of a note, defaulting to the current one
+    Show {
+        note: Uuid,
+        /// An index from `history`
+        #[structopt(long, conflicts_with = "at")]
+        index: Option<usize>,
+        /// A UTC epoch time, to show the version that was current then
+        #[structopt(long)]
+        at: Option<i64>,
+    },
+    /// Makes a past version of a note the current one
+    Restore {
+        note: Uuid,
+        /// An index from `history`
+        #[structopt(long, conflicts_with = "at", required_unless = "at")]
+        index: Option<usize>,
+        /// A UTC epoch time, to restore the version that was current then
+        #[structopt(long)]
+        at: Option<i64>,
+    },
+    /// Deletes blobs that aren't used by any note or note history
+    Gc {
+        #[structopt(long)]
+        dry_run: bool,
+        /// Shrink the database file afterwards
+        #[structopt(long)]
+        vacuum: bool,
+    },
+    /// Checks the repository for corruption
+    Fsck {
+        /// Fix the problems that can be fixed without losing information
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// Writes a copy of the repository to a bundle file, for syncing with import-bundle
+    ExportBundle {
+        path: PathBuf,
+        /// Only include what the peer doesn't already have
+        #[structopt(long)]
+        peer: Option<String>,
+    },
+    /// Merges a bundle written by export-bundle into the repository
+    ImportBundle {
+        path: PathBuf,
+        /// The peer that the bundle came from, so that the next bundle for it can leave out what it has
+        #[structopt(long)]
+        peer: Option<String>,
+    },
+    /// Lists notes with changes from another repository that couldn't be merged
+    Conflicts {
+        /// Show how the other repository's version differs from ours
+        #[structopt(long)]
+        diff: bool,
+    },
+    /// Resolves a conflict by keeping one version, or by replacing them with the contents of a file
+    ResolveConflict {
+        note: Uuid,
+        #[structopt(long, possible_values = &["ours", "theirs", "base"], required_unless = "file")]
+        keep: Option<String>,
+        #[structopt(long, conflicts_with = "keep")]
+        file: Option<PathBuf>,
+    },
+    /// Lists every change made to the repository, oldest first
+    Log {
+        /// Only list changes to this note
+        #[structopt(long)]
+        note: Option<Uuid>,
+    },
+    /// Lists the peers that bundles have been exchanged with
+    Peers,
+    /// Forgets what a peer has, so that the next bundle for it includes everything
+    ForgetPeer {
+        peer: String,
+    },
+    /// Upgrades the repository to the latest schema version
+    Migrate {
+        /// Only list the pending migrations
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Links a note to another note or to a URL
+    Link {
+        from: Uuid,
+        #[structopt(parse(from_str = parse_link_target))]
+        to: LinkTarget,
+        #[structopt(long, default_value = ANNOTATION_LINK)]
+        kind: String,
+        /// The part of the note the link is attached to, like text:120-250
+        #[structopt(long)]
+        from_anchor: Option<Anchor>,
+        /// The part of the linked note the link points at, like pdf:3 or media:65.5-90
+        #[structopt(long)]
+        to_anchor: Option<Anchor>,
+    },
+    Unlink {
+        link: Uuid,
+    },
+    /// Lists the links from a note, and the links to it from other notes
+    Links {
+        note: Uuid,
+    },
+    /// Lists the spaced repetition cards in a note
+    Cards {
+        note: Uuid,
+    },
+    /// Lists cards whose text is no longer in their note
+    OrphanedCards,
+    /// Lists cards whose text has changed since they were last reviewed
+    ChangedCards,
+    DeleteCard {
+        card: Uuid,
+    },
+    /// Reviews the cards that are due
+    Review {
+        /// Stop after this many cards
+        #[structopt(long)]
+        limit: Option<usize>,
+    },
+    /// Shows changes between two versions, defaulting to the last version with different contents
+    /// and the current version
+    Diff {
+        note: Uuid,
+        /// An index from `history`
+        from: Option<usize>,
+        /// An index from `history`
+        to: Option<usize>,
+    },
+}
+
+// Versions can be referred to either by their index in the output of `history`, or by a time, in
+// which case the version that was current at that time is used. None means the current version,
+// which is also what's current at any time after the last change.
+fn find_version(db: &Connection, note: Uuid, index: Option<usize>, at: Option<i64>) -> Result<Option<NoteHistoryEntry>, CommonplaceError> {
+    let mut history = get_note_history(db, note)?;
+    match (index, at) {
+        (Some(index), _) if index < history.len() => Ok(Some(history.remove(index))),
+        (Some(index), _) if index == history.len() => Ok(None),
+        (Some(index), _) => Err(io::Error::new(io::ErrorKind::NotFound, format!("note {} has no version {}", note, index)).into()),
+        (None, Some(at)) => Ok(history.into_iter().find(|x| x.time > at)),
+        (None, None) => Ok(None),
+    }
+}
+
+fn version_hash(db: &Connection, note: Uuid, version: Option<NoteHistoryEntry>) -> Result<[u8; 32], CommonplaceError> {
+    match version {
+        Some(version) => Ok(version.hash),
+        None => Ok(get_note(db, note)?.hash),
+    }
+}
+
+// Tags that have since been deleted are named by the last name the log has for them.
+fn describe_operation(notes: &HashMap<Uuid, Note>, tags: &TagForest, old_tags: &HashMap<TagId, String>, operation: &Operation) -> String {
+    let note = |id: &NoteId| notes.get(&id.uuid).map(|x| x.name.clone()).unwrap_or_else(|| id.to_string());
+    let tag = |id: &TagId| tags.tag_name(id).map(|x| x.join("::"))
+        .or_else(|| old_tags.get(id).cloned())
+        .unwrap_or_else(|| id.to_string());
+    let parent = |id: &Option<TagId>| id.as_ref().map(tag).unwrap_or_else(|| "the top level".to_string());
+    let hash = |hash: &[u8; 32]| hex::encode(hash)[..16].to_string();
+    match operation {
+        Operation::AddNote { note } => format!("added note {}", note.name),
+        Operation::UpdateNote { note: id, from, to } => format!("updated note {} from {} to {}", note(id), hash(from), hash(to)),
+        Operation::RenameNote { from, to, .. } => format!("renamed note {} to {}", from, to),
+        Operation::RestoreNoteVersion { to, .. } => format!("restored note {} to {}", to.name, hash(&to.hash)),
+        Operation::DeleteNote { note } => format!("deleted note {}", note.name),
+        Operation::ResolveConflict { note: id, to, .. } => format!("resolved conflict in note {} with {}", note(id), hash(to)),
+        Operation::RecordConflict { note: id, ours, theirs, .. } => format!("recorded conflict in note {} between {} and {}", note(id), hash(ours), hash(theirs)),
+        Operation::CreateTag { tag: row } => format!("created tag {} under {}", row.name, parent(&row.parent)),
+        Operation::RenameTag { from, to, .. } => format!("renamed tag {} to {}", from, to),
+        Operation::MoveTag { tag: id, from, to } => format!("moved tag {} from {} to {}", tag(id), parent(from), parent(to)),
+        Operation::MergeTags { src, dst } => format!("merged tag {} into {}", src.name, tag(dst)),
+        Operation::DeleteTag { tag: row, .. } => format!("deleted tag {}", row.name),
+        Operation::SetTagHidden { tag: id, hidden: true } => format!("hid tag {}", tag(id)),
+        Operation::SetTagHidden { tag: id, hidden: false } => format!("unhid tag {}", tag(id)),
+        Operation::TagNote { note: id, tag: tag_id } => format!("tagged note {} with {}", note(id), tag(tag_id)),
+        Operation::UntagNote { note: id, tag: tag_id } => format!("untagged note {} from {}", note(id), tag(tag_id)),
+        Operation::AddLink { link } => format!("linked note {} to {}", note(&link.from), link.to),
+        Operation::DeleteLink { link } => format!("unlinked note {} from {}", note(&link.from), link.to),
+        Operation::ReviewCard { card, quality, to, .. } => format!("reviewed card {} with quality {}, next in {} days", card.uuid, quality, to.interval),
+        Operation::DeleteCard { card, reviews } => format!("deleted card {} and its {} reviews", card.id.uuid, reviews.len()),
+        Operation::ImportBundle { notes_added, notes_updated, notes_merged, notes_deleted, tags, conflicts, history, blobs, .. } => format!(
+            "imported a bundle: {} notes added, {} updated, {} merged, {} deleted, {} conflicts, {} tags, {} history entries, {} blobs",
+            notes_added, notes_updated, notes_merged, notes_deleted, conflicts, tags, history, blobs
+        ),
+        Operation::RepairRepository { changes } => format!("repaired {} problems", changes.len()),
+        Operation::CollectGarbage { blobs } => format!("deleted {} unreferenced blobs", blobs.len()),
+    }
+}
+
+fn print_card(card: &Card) {
+    println!(
+        "{}\t{}\t{}\t{}\t{}",
+        card.id, card.note_id, if card.orphaned { "orphaned" } else { "" }, card.front.replace("\n", " "), card.back.replace("\n", " ")
+    );
+}
+
+// None at the end of input.
+fn read_line(prompt: &str) -> Result<Option<String>, CommonplaceError> {
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+fn review(db: &Connection, limit: Option<usize>) -> Result<(), CommonplaceError> {
+    let mut reviewed = 0;
+    'cards: for card in get_due_cards(db)?.into_iter().take(limit.unwrap_or(usize::MAX)) {
+        println!("{}\n", card.front);
+        if read_line("(enter to show the answer) ")?.is_none() {
+            break;
+        }
+        println!("{}\n", card.back);
+        let grade = loop {
+            match read_line("1 again, 2 hard, 3 good, 4 easy, q to quit: ")?.as_deref() {
+                Some("1") => break Grade::Again,
+                Some("2") => break Grade::Hard,
+                Some("3") => break Grade::Good,
+                Some("4") => break Grade::Easy,
+                Some("q") | None => break 'cards,
+                _ => {},
+            }
+        };
+        let card = review_card(db, card.id.uuid, grade)?;
+        reviewed += 1;
+        println!("next review in {} days\n", card.schedule.interval);
+    }
+    println!("reviewed {} cards", reviewed);
+    Ok(())
 }
 
 fn import_directory(db: &Connection, directory: PathBuf) {
@@ -84,9 +393,15 @@ fn import_directory(db: &Connection, directory: PathBuf) {
 }
 
 fn main() -> Result<(), CommonplaceError> {
-    let cmdline = Cmdline::from_args();
+    let args = Args::from_args();
+    let cmdline = args.cmdline;
+    let repo = args.repo.as_deref();
 
-    let db = open_db()?;
+    let db = match cmdline {
+        Cmdline::Init { .. } => open_db(&new_repo_path(repo)?)?,
+        Cmdline::Migrate { .. } => open_db_unmigrated(&find_repo(repo)?)?,
+        _ => open_db(&find_repo(repo)?)?,
+    };
 
     match cmdline {
         Cmdline::Init { directory } => {
@@ -95,14 +410,201 @@ fn main() -> Result<(), CommonplaceError> {
                 import_directory(&db, directory);
             }
         },
-        Cmdline::ShowTree {} => { for tree in get_tag_tree(&db)? { println!("{}", tree); } },
+        Cmdline::Gc { dry_run, vacuum: should_vacuum } => {
+            let report = gc(&db, dry_run)?;
+            println!("{} {} blobs ({} bytes)", if dry_run { "would delete" } else { "deleted" }, report.blobs, report.bytes);
+            if should_vacuum && !dry_run {
+                vacuum(&db)?;
+            }
+        },
+        Cmdline::Fsck { repair } => {
+            let problems = check_repository(&db)?;
+            for problem in &problems {
+                println!("{}{}", problem, if problem.is_repairable() { " (repairable)" } else { "" });
+            }
+            let repaired = if repair { repair_repository(&db, &problems)? } else { 0 };
+            if repair {
+                println!("repaired {} problems", repaired);
+            }
+            if problems.len() > repaired {
+                std::process::exit(1);
+            }
+        },
+        Cmdline::ExportBundle { path, peer } => export_bundle(&db, &path, peer.as_deref())?,
+        Cmdline::ImportBundle { path, peer } => {
+            let report = import_bundle(&db, &path, peer.as_deref())?;
+            println!(
+                "added {} notes, updated {}, merged {}, deleted {}; added {} tags and {} blobs",
+                report.notes_added, report.notes_updated, report.notes_merged, report.notes_deleted, report.tags, report.blobs
+            );
+            if report.conflicts > 0 {
+                println!("{} notes have conflicting changes", report.conflicts);
+            }
+        },
+        Cmdline::Conflicts { diff } => {
+            let notes = get_all_notes(&db)?;
+            for conflict in list_conflicts(&db)? {
+                let name = notes.get(&conflict.note_id.uuid).map(|x| x.name.as_str()).unwrap_or("");
+                println!("{}\t{}\t{}", conflict.note_id, conflict.time, name);
+                if diff {
+                    for hunk in diff_blobs(&db, conflict.ours.into(), conflict.theirs.into())? {
+                        print!("{}", hunk);
+                    }
+                }
+            }
+        },
+        Cmdline::ResolveConflict { note, keep, file } => {
+            let resolution = match (keep.as_deref(), file) {
+                (_, Some(file)) => Resolution::Merged(std::fs::read(file)?),
+                (keep, None) => {
+                    let conflict = get_conflict(&db, note)?;
+                    Resolution::Keep(match keep {
+                        Some("base") => conflict.base,
+                        Some("theirs") => conflict.theirs,
+                        _ => conflict.ours,
+                    })
+                },
+            };
+            resolve_conflict(&db, note, resolution)?;
+        },
+        Cmdline::Log { note } => {
+            let notes = get_all_notes(&db)?;
+            let tags = get_tag_tree(&db, true)?;
+            let mut old_tags = HashMap::new();
+            for entry in get_operation_log(&db, None)? {
+                match entry.operation {
+                    Operation::CreateTag { tag } | Operation::DeleteTag { tag, .. } | Operation::MergeTags { src: tag, .. } => { old_tags.insert(tag.id, tag.name); },
+                    Operation::RenameTag { tag, to, .. } => { old_tags.insert(tag, to); },
+                    _ => {},
+                }
+            }
+            for entry in get_operation_log(&db, note)? {
+                let device = entry.time.device.map(|x| x.to_string()[..8].to_string()).unwrap_or_default();
+                println!("{}\t{}\t{}", entry.time.millis() / 1000, device, describe_operation(&notes, &tags, &old_tags, &entry.operation));
+            }
+        },
+        Cmdline::Peers => {
+            for peer in get_peers(&db)? {
+                println!("{}\t{} blobs\t{} unacknowledged bundles", peer.name, peer.blobs, peer.unacknowledged);
+            }
+        },
+        Cmdline::ForgetPeer { peer } => forget_peer(&db, &peer)?,
+        Cmdline::Migrate { dry_run } => {
+            let migrations = if dry_run { pending_migrations(&db)? } else { migrate_db(&db)? };
+            for migration in migrations {
+                println!("{}\t{}", migration.version, migration.name);
+            }
+        },
+        Cmdline::ShowTree { include_hidden } => print!("{}", get_tag_tree(&db, include_hidden)?),
         Cmdline::AddNote { name, filename } => { println!("{}", add_note(&db, name, filename)?); },
         Cmdline::UpdateNote { note, filename } => update_note(&db, note, filename)?,
         Cmdline::RenameNote { note, name } => rename_note(&db, note, name)?,
+        Cmdline::DeleteNote { note } => delete_note(&db, note)?,
+        Cmdline::Archive { note } => archive_note(&db, note)?,
+        Cmdline::Unarchive { note } => unarchive_note(&db, note)?,
         Cmdline::CreateTag { tag } => create_tag(&db, tag.0)?,
         Cmdline::DeleteTag { tag } => delete_tag(&db, tag.0)?,
+        Cmdline::RenameTag { tag, name } => rename_tag(&db, get_tag_id_by_name(&db, tag.0)?, name)?,
+        Cmdline::MoveTag { tag, parent } => {
+            let parent = parent.map(|parent| get_tag_id_by_name(&db, parent.0)).transpose()?;
+            move_tag(&db, get_tag_id_by_name(&db, tag.0)?, parent)?
+        },
+        Cmdline::MergeTag { src, dst } => merge_tags(&db, get_tag_id_by_name(&db, src.0)?, get_tag_id_by_name(&db, dst.0)?)?,
+        Cmdline::CanonicalizeTags { dry_run } => {
+            let tag_tree = get_tag_tree(&db, true)?;
+            for (note, tag) in remove_redundant_tags(&db, dry_run)? {
+                println!("{}\t{}", note, tag_tree.tag_name(&tag).unwrap().join("::"));
+            }
+        },
+        Cmdline::HideTag { tag } => set_tag_hidden(&db, get_tag_id_by_name(&db, tag.0)?, true)?,
+        Cmdline::UnhideTag { tag } => set_tag_hidden(&db, get_tag_id_by_name(&db, tag.0)?, false)?,
         Cmdline::TagNote { note, tag } => tag_note(&db, note, tag.0)?,
         Cmdline::UntagNote { note, tag } => untag_note(&db, note, tag.0)?,
+        Cmdline::Query { include_hidden, query } => {
+            let notes = get_all_notes(&db)?;
+            for note_id in query_notes(&db, &parse_query(&query.join(" "))?, include_hidden)? {
+                println!("{}\t{}", note_id, notes[&note_id.uuid].name);
+            }
+        },
+        Cmdline::Search { query } => {
+            for hit in search_notes(&db, &query.join(" "))? {
+                println!("{}\t{}\t{}", hit.note_id, hit.name, hit.snippet.replace("\n", " "));
+            }
+        },
+        Cmdline::History { note } => {
+            let history = get_note_history(&db, note)?;
+            for (i, version) in history.iter().enumerate() {
+                println!("{}\t{}\t{}\t{}", i, version.time, &hex::encode(version.hash)[..16], version.name);
+            }
+            println!("{}\tcurrent", history.len());
+        },
+        Cmdline::Show { note, index, at } => {
+            let contents = match find_version(&db, note, index, at)? {
+                Some(version) => get_note_version_contents(&db, &version)?,
+                None => get_note_contents(&db, note)?,
+            };
+            std::io::stdout().write_all(&contents)?;
+        },
+        Cmdline::Diff { note, from, to } => {
+            let from = match from {
+                Some(from) => find_version(&db, note, Some(from), None)?,
+                None => match get_previous_contents(&db, note)? {
+                    Some(version) => Some(version),
+                    None => {
+                        println!("the contents of note {} have never changed", note);
+                        return Ok(());
+                    },
+                },
+            };
+            let to = find_version(&db, note, to, None)?;
+            let from = version_hash(&db, note, from)?;
+            let to = version_hash(&db, note, to)?;
+            for hunk in diff_blobs(&db, from.into(), to.into())? {
+                print!("{}", hunk);
+            }
+        },
+        Cmdline::Link { from, to, kind, from_anchor, to_anchor } => {
+            println!("{}", add_link(&db, from, NewLink { from_anchor, to, to_anchor, kind })?);
+        },
+        Cmdline::Unlink { link } => delete_link(&db, link)?,
+        Cmdline::Links { note } => {
+            let notes = get_all_notes(&db)?;
+            let anchor = |anchor: &Option<Anchor>| anchor.as_ref().map(|x| x.to_string()).unwrap_or_default();
+            for link in get_links(&db, note)? {
+                let name = match &link.to {
+                    LinkTarget::Note(to) => notes.get(&to.uuid).map(|x| x.name.as_str()).unwrap_or(""),
+                    LinkTarget::Url(_) => "",
+                };
+                println!("{}\t{}\t{}\t->\t{}\t{}\t{}", link.id, link.kind, anchor(&link.from_anchor), link.to, anchor(&link.to_anchor), name);
+            }
+            for link in get_backlinks(&db, note)? {
+                let name = notes.get(&link.from.uuid).map(|x| x.name.as_str()).unwrap_or("");
+                println!("{}\t{}\t{}\t<-\t{}\t{}\t{}", link.id, link.kind, anchor(&link.to_anchor), link.from, anchor(&link.from_anchor), name);
+            }
+        },
+        Cmdline::Cards { note } => {
+            for card in get_cards_for_note(&db, note)? {
+                print_card(&card);
+            }
+        },
+        Cmdline::OrphanedCards => {
+            for card in get_orphaned_cards(&db)? {
+                print_card(&card);
+            }
+        },
+        Cmdline::ChangedCards => {
+            for changed in get_changed_cards(&db)? {
+                println!("{}\t{}\treviewed at {}", changed.card.id, changed.card.note_id, changed.reviewed_time);
+                println!("-\t{}\t{}", changed.reviewed.front.replace("\n", " "), changed.reviewed.back.replace("\n", " "));
+                println!("+\t{}\t{}", changed.card.front.replace("\n", " "), changed.card.back.replace("\n", " "));
+            }
+        },
+        Cmdline::DeleteCard { card } => delete_card(&db, card)?,
+        Cmdline::Review { limit } => review(&db, limit)?,
+        Cmdline::Restore { note, index, at } => match find_version(&db, note, index, at)? {
+            Some(version) => restore_note_version(&db, note, &version)?,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("that's already the current version of note {}", note)).into()),
+        },
     }
 
     Ok(())
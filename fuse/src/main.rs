@@ -4,9 +4,10 @@ use fuser::{
 };
 use libc::ENOENT;
 use crate::inode_map::{InodeMap, ROOT_INODE};
-use libcommonplace_types::NoteOrTag;
+use libcommonplace_types::{NoteOrTag, ROOT_TAG};
 use std::env;
 use std::ffi::OsStr;
+use std::path::Path;
 use std::time::{Duration, UNIX_EPOCH};
 use std::convert::TryInto;
 
@@ -60,13 +61,15 @@ fn dir_attr(ino: u64) -> FileAttr {
 struct FS {
     inode_map: InodeMap,
     db: libcommonplace::Connection,
+    include_hidden: bool,
 }
 
 impl FS {
-    fn new() -> Self {
+    fn new(repo: &Path, include_hidden: bool) -> Self {
         Self {
             inode_map: InodeMap::new(),
-            db: libcommonplace::open_db().unwrap(),
+            db: libcommonplace::open_db(repo).unwrap(),
+            include_hidden,
         }
     }
 }
@@ -75,21 +78,20 @@ impl Filesystem for FS {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         //println!("lookup(parent = {:?}, name = {:?})", parent, name);
 
-        let tagtree = libcommonplace::get_tag_tree(&self.db).unwrap();
+        let tagtree = libcommonplace::get_tag_tree(&self.db, self.include_hidden).unwrap();
         let all_notes = libcommonplace::get_all_notes(&self.db).unwrap();
 
-        let (children, notes) = if parent == ROOT_INODE {
+        let (tag, notes) = if parent == ROOT_INODE {
             let untagged_notes = libcommonplace::get_untagged_notes(&self.db).unwrap();
-            (tagtree, untagged_notes)
+            (ROOT_TAG, untagged_notes)
         } else {
             let uuid = match self.inode_map.get_entry(parent) {
                 Some(entry) => entry.uuid,
                 None => return reply.error(ENOENT),
             };
 
-
-            if let Some(NoteOrTag::Tag(parent)) = libcommonplace_types::get_by_uuid(&tagtree, &all_notes, uuid) {
-                (parent.children.clone(), parent.notes.iter().map(|x| x.uuid).collect())
+            if let Some(NoteOrTag::Tag(parent)) = tagtree.get_by_uuid(&all_notes, uuid) {
+                (parent.id, parent.notes.iter().map(|x| x.uuid).collect())
             } else {
                 return reply.error(ENOENT);
             }
@@ -103,7 +105,7 @@ impl Filesystem for FS {
             }
         }
 
-        for tag in &children {
+        for tag in tagtree.children(&tag) {
             if name.to_str() == Some(&tag.name) {
                 return reply.entry(&TTL, &dir_attr(self.inode_map.get_inode(inode_map::Entry { type_: inode_map::Type::TAG, uuid: tag.id.uuid })), 0);
                 
@@ -167,16 +169,16 @@ impl Filesystem for FS {
 
         let mut entries = vec![];
 
-        let tagtree = libcommonplace::get_tag_tree(&self.db).unwrap();
+        let tagtree = libcommonplace::get_tag_tree(&self.db, self.include_hidden).unwrap();
         let notes = libcommonplace::get_all_notes(&self.db).unwrap();
 
         if ino == ROOT_INODE {
-            for tag in tagtree {
+            for tag in tagtree.children(&ROOT_TAG) {
                 let inode = self.inode_map.get_inode(inode_map::Entry {
                     type_: inode_map::Type::TAG,
                     uuid: tag.id.uuid,
                 });
-                entries.push((inode, FileType::Directory, tag.name));
+                entries.push((inode, FileType::Directory, tag.name.clone()));
             }
             let untagged_notes = libcommonplace::get_untagged_notes(&self.db).unwrap();
             for note_id in untagged_notes {
@@ -193,11 +195,11 @@ impl Filesystem for FS {
                 None => return reply.error(ENOENT),
             };
 
-            let details = libcommonplace_types::get_by_uuid(&tagtree, &notes, entry.uuid);
+            let details = tagtree.get_by_uuid(&notes, entry.uuid);
 
             match details {
                 Some(NoteOrTag::Tag(tag)) => {
-                    for tag in &tag.children {
+                    for tag in tagtree.children(&tag.id) {
                         let inode = self.inode_map.get_inode(inode_map::Entry {
                             type_: inode_map::Type::TAG,
                             uuid: tag.id.uuid,
@@ -228,7 +230,31 @@ impl Filesystem for FS {
 }
 
 fn main() {
-    let mountpoint = env::args_os().nth(1).unwrap();
+    let mut repo = None;
+    let mut include_hidden = false;
+    let mut mountpoint = None;
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--repo" {
+            repo = args.next().map(|x| x.to_string_lossy().to_string());
+        } else if arg == "--include-hidden" {
+            include_hidden = true;
+        } else if mountpoint.is_none() {
+            mountpoint = Some(arg);
+        } else {
+            mountpoint = None;
+            break;
+        }
+    }
+    let mountpoint = match mountpoint {
+        Some(mountpoint) => mountpoint,
+        None => {
+            eprintln!("usage: commonplace-fuse [--repo REPO] [--include-hidden] MOUNTPOINT");
+            std::process::exit(1);
+        },
+    };
+    let repo = libcommonplace::find_repo(repo.as_deref()).unwrap();
+
     let options = vec![MountOption::RO, MountOption::AutoUnmount, MountOption::FSName("commonplace".to_string())];
-    fuser::mount2(FS::new(), mountpoint, &options).unwrap();
+    fuser::mount2(FS::new(&repo, include_hidden), mountpoint, &options).unwrap();
 }
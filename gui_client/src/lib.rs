@@ -6,7 +6,7 @@ use enclose::enc;
 
 use std::collections::HashMap;
 
-use libcommonplace_types::{NoteId, TagId, Note, TagTree, get_tags_for_note, get_tag_name, get_tag_by_full_name};
+use libcommonplace_types::{NoteId, TagId, Note, TagForest, ROOT_TAG, Link, ANNOTATION_LINK, Anchor, ResolvedAnchor, Conflict, Resolution};
 
 fn init(_: Url, orders: &mut impl Orders<Msg>) -> Model {
     orders.stream(streams::window_event(Ev::KeyDown, |event| {
@@ -17,10 +17,14 @@ fn init(_: Url, orders: &mut impl Orders<Msg>) -> Model {
     Model {
         tag_tree: None,
         tag_tree_folds: HashMap::new(),
+        dragged_tag: None,
+        show_hidden: false,
         sidebar_tab: SidebarTab::TagTree,
         notes: HashMap::new(),
         untagged_notes: vec![],
         current_note: None,
+        annotations: vec![],
+        conflict: None,
         note_text: None,
         note_dirty: false,
         should_reload_slate: false,
@@ -28,12 +32,18 @@ fn init(_: Url, orders: &mut impl Orders<Msg>) -> Model {
 }
 
 struct Model {
-    tag_tree: Option<Vec<TagTree>>,
+    tag_tree: Option<TagForest>,
     tag_tree_folds: HashMap<TagId, bool>,
+    dragged_tag: Option<TagId>,
+    show_hidden: bool,
     sidebar_tab: SidebarTab,
     notes: HashMap<NoteId, Note>,
     untagged_notes: Vec<NoteId>,
     current_note: Option<NoteId>,
+    // Annotation links from other notes to the current note.
+    annotations: Vec<Link>,
+    // The current note's conflict, if it has one, with the text of our head and theirs.
+    conflict: Option<(Conflict, String, String)>,
     note_text: Option<String>,
     note_dirty: bool,
     should_reload_slate: bool, // this is a hack.
@@ -47,15 +57,28 @@ enum SidebarTab {
 
 enum Msg {
     RequestUpdateTagTree,
-    UpdateTagTree((Vec<TagTree>, HashMap<NoteId, Note>, Vec<NoteId>)),
+    UpdateTagTree((TagForest, HashMap<NoteId, Note>, Vec<NoteId>)),
     ToggleTag(TagId),
+    ToggleShowHidden,
     OpenNote(NoteId),
     NoteBlobLoaded(String),
+    AnnotationsLoaded(Vec<Link>),
+    ConflictLoaded(Option<(Conflict, String, String)>),
+    ResolveConflict((NoteId, Resolution)),
+    FollowAnchor((NoteId, Anchor)),
+    AnchorResolved(ResolvedAnchor),
     RenameNote((Option<NoteId>, String)),
+    DeleteNote(NoteId),
+    ArchiveNote(NoteId),
     AddTagToNote((NoteId, TagId)),
     UntagNote((NoteId, TagId)),
     CreateTag(Vec<String>),
     DeleteTag(TagId),
+    RenameTag((TagId, String)),
+    DragTag(TagId),
+    // Dropping on the sidebar rather than on a tag moves the dragged tag to the root.
+    DropTag(Option<TagId>),
+    MergeDroppedTag(TagId),
     KeyPressed(web_sys::KeyboardEvent),
     UpdateNoteText(String),
     SaveNote,
@@ -66,8 +89,9 @@ enum Msg {
 fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     match msg {
         Msg::RequestUpdateTagTree => {
-            orders.skip().perform_cmd(async {
-                get_tag_tree().await.map(|t| Msg::UpdateTagTree(t)).ok()
+            let show_hidden = model.show_hidden;
+            orders.skip().perform_cmd(async move {
+                get_tag_tree(show_hidden).await.map(|t| Msg::UpdateTagTree(t)).ok()
             });
         },
         Msg::UpdateTagTree((tag_tree, notes, untagged_notes)) => {
@@ -81,6 +105,10 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         Msg::ToggleTag(uuid) => {
             *model.tag_tree_folds.entry(uuid).or_insert(false) ^= true;
         },
+        Msg::ToggleShowHidden => {
+            model.show_hidden = !model.show_hidden;
+            orders.send_msg(Msg::RequestUpdateTagTree);
+        },
         Msg::OpenNote(note) => {
             if model.note_dirty {
                 orders.send_msg(Msg::SaveNote);
@@ -88,6 +116,13 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                 model.note_dirty = false;
             } else {
                 model.current_note = Some(note);
+                model.conflict = None;
+                orders.perform_cmd(async move {
+                    get_backlinks(note).await.map(|l| Msg::AnnotationsLoaded(l)).ok()
+                });
+                orders.perform_cmd(async move {
+                    get_conflict(note).await.map(|c| Msg::ConflictLoaded(c)).ok()
+                });
                 if let Some(hash) = model.notes.get(&note).map(|x| x.hash) {
                     orders.perform_cmd(enc!((hash) async move {
                         get_blob(&hex::encode(&hash)).await.map(|b| Msg::NoteBlobLoaded(b)).ok()
@@ -102,6 +137,37 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             model.should_reload_slate = false;
             update_slate(&blob);
         },
+        Msg::AnnotationsLoaded(links) => {
+            model.annotations = links.into_iter().filter(|x| x.kind == ANNOTATION_LINK).collect();
+        },
+        Msg::ConflictLoaded(conflict) => {
+            model.conflict = conflict;
+        },
+        Msg::ResolveConflict((note, resolution)) => {
+            model.conflict = None;
+            model.note_dirty = false;
+            model.should_reload_slate = true;
+            orders.perform_cmd(async move {
+                resolve_conflict(note, resolution).await;
+                Msg::RequestUpdateTagTree
+            });
+        },
+        Msg::FollowAnchor((note, anchor)) => {
+            orders.skip().perform_cmd(async move {
+                resolve_anchor(note, anchor).await.map(|a| Msg::AnchorResolved(a)).ok()
+            });
+        },
+        Msg::AnchorResolved(resolved) => {
+            orders.skip();
+            // Text notes are open in the editor already, everything else is left to the browser.
+            match (resolved.line, resolved.anchor.fragment()) {
+                (Some(line), _) => scroll_slate_to_line(line as u32),
+                (None, Some(fragment)) => {
+                    window().open_with_url(&format!("/api/note/{}/contents#{}", resolved.note, fragment)).ok();
+                },
+                (None, None) => {},
+            }
+        },
         Msg::RenameNote((uuid, name)) => {
             if let Some(uuid) = uuid.or(model.current_note.or(None)) {
                 orders.skip().perform_cmd(async move {
@@ -110,6 +176,28 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                 });
             }
         },
+        Msg::DeleteNote(note) => {
+            if window().confirm_with_message("Delete this note and all of its history?").unwrap_or(false) {
+                if model.current_note == Some(note) {
+                    model.current_note = None;
+                    model.annotations = vec![];
+                    model.conflict = None;
+                    model.note_text = None;
+                    model.note_dirty = false;
+                    update_slate("");
+                }
+                orders.perform_cmd(async move {
+                    delete_note(note).await;
+                    Msg::RequestUpdateTagTree
+                });
+            }
+        },
+        Msg::ArchiveNote(note) => {
+            orders.skip().perform_cmd(async move {
+                archive_note(note).await;
+                Msg::RequestUpdateTagTree
+            });
+        },
         Msg::AddTagToNote((note, tag)) => {
             orders.skip().perform_cmd(async move {
                 add_tag_to_note(note, tag).await;
@@ -134,6 +222,38 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                 Msg::RequestUpdateTagTree
             });
         }
+        Msg::RenameTag((tag_id, name)) => {
+            orders.skip().perform_cmd(async move {
+                rename_tag(tag_id, name).await;
+                Msg::RequestUpdateTagTree
+            });
+        }
+        Msg::DragTag(tag_id) => {
+            orders.skip();
+            model.dragged_tag = Some(tag_id);
+        }
+        Msg::DropTag(parent) => {
+            orders.skip();
+            if let Some(tag_id) = model.dragged_tag.take() {
+                if Some(tag_id) != parent {
+                    orders.perform_cmd(async move {
+                        move_tag(tag_id, parent).await;
+                        Msg::RequestUpdateTagTree
+                    });
+                }
+            }
+        }
+        Msg::MergeDroppedTag(dst) => {
+            orders.skip();
+            if let Some(src) = model.dragged_tag.take() {
+                if src != dst && window().confirm_with_message("Merge this tag into the other one?").unwrap_or(false) {
+                    orders.perform_cmd(async move {
+                        merge_tag(src, dst).await;
+                        Msg::RequestUpdateTagTree
+                    });
+                }
+            }
+        }
         Msg::SaveNote => {
             match (model.current_note, model.note_text.as_ref()) {
                 (Some(uuid), Some(text)) => {
@@ -174,8 +294,8 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     }
 }
 
-async fn get_tag_tree() -> Result<(Vec<TagTree>, HashMap<NoteId, Note>, Vec<NoteId>), ()> {
-    let bytes = Request::new("/api/showtree")
+async fn get_tag_tree(show_hidden: bool) -> Result<(TagForest, HashMap<NoteId, Note>, Vec<NoteId>), ()> {
+    let bytes = Request::new(if show_hidden { "/api/showtree?hidden=1" } else { "/api/showtree" })
         .method(Method::Get)
         .fetch()
         .await.map_err(|e| { log!(e); })?
@@ -212,6 +332,54 @@ async fn get_blob(hash: &str) -> Result<String, ()> {
     String::from_utf8(bytes).map_err(|e| { log!(e) })
 }
 
+async fn get_backlinks(uuid: NoteId) -> Result<Vec<Link>, ()> {
+    let bytes = Request::new(format!("/api/note/{}/backlinks", uuid))
+        .method(Method::Get)
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?
+        .bytes().map_err(|e| { log!(e) }).await?;
+    serde_json::from_slice(&bytes[..]).map_err(|e| { log!(e) })
+}
+
+// None if the note doesn't have a conflict.
+async fn get_conflict(uuid: NoteId) -> Result<Option<(Conflict, String, String)>, ()> {
+    let response = Request::new(format!("/api/conflicts/{}", uuid))
+        .method(Method::Get)
+        .fetch()
+        .await.map_err(|e| { log!(e); })?;
+    if response.status().code == 404 {
+        return Ok(None);
+    }
+    let bytes = response
+        .check_status().map_err(|e| { log!(e); })?
+        .bytes().map_err(|e| { log!(e) }).await?;
+    let conflict: Conflict = serde_json::from_slice(&bytes[..]).map_err(|e| { log!(e) })?;
+    let ours = get_blob(&hex::encode(&conflict.ours)).await?;
+    let theirs = get_blob(&hex::encode(&conflict.theirs)).await?;
+    Ok(Some((conflict, ours, theirs)))
+}
+
+async fn resolve_conflict(uuid: NoteId, resolution: Resolution) -> Result<(), ()> {
+    Request::new(format!("/api/conflicts/{}", uuid))
+        .method(Method::Post)
+        .body(serde_json::to_string(&resolution).unwrap().into())
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?;
+    Ok(())
+}
+
+async fn resolve_anchor(uuid: NoteId, anchor: Anchor) -> Result<ResolvedAnchor, ()> {
+    let bytes = Request::new(format!("/api/note/{}/resolve?anchor={}", uuid, anchor))
+        .method(Method::Get)
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?
+        .bytes().map_err(|e| { log!(e) }).await?;
+    serde_json::from_slice(&bytes[..]).map_err(|e| { log!(e) })
+}
+
 async fn rename_note(uuid: NoteId, name: String) -> Result<(), ()> {
     Request::new(format!("/api/note/{}/rename", uuid))
         .method(Method::Post)
@@ -222,6 +390,24 @@ async fn rename_note(uuid: NoteId, name: String) -> Result<(), ()> {
     Ok(())
 }
 
+async fn delete_note(uuid: NoteId) -> Result<(), ()> {
+    Request::new(format!("/api/note/{}", uuid))
+        .method(Method::Delete)
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?;
+    Ok(())
+}
+
+async fn archive_note(uuid: NoteId) -> Result<(), ()> {
+    Request::new(format!("/api/note/{}/archive", uuid))
+        .method(Method::Post)
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?;
+    Ok(())
+}
+
 async fn add_tag_to_note(note: NoteId, tag: TagId) -> Result<(), ()> {
     Request::new(format!("/api/note/{}/tag/{}", note, tag))
         .method(Method::Post)
@@ -260,6 +446,35 @@ async fn delete_tag(tag: TagId) -> Result<(), ()> {
     Ok(())
 }
 
+async fn rename_tag(tag: TagId, name: String) -> Result<(), ()> {
+    Request::new(format!("/api/tag/{}/rename", tag))
+        .method(Method::Post)
+        .body(name.into())
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?;
+    Ok(())
+}
+
+async fn move_tag(tag: TagId, parent: Option<TagId>) -> Result<(), ()> {
+    Request::new(format!("/api/tag/{}/move", tag))
+        .method(Method::Post)
+        .body(serde_json::to_string(&parent).unwrap().into())
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?;
+    Ok(())
+}
+
+async fn merge_tag(src: TagId, dst: TagId) -> Result<(), ()> {
+    Request::new(format!("/api/tag/{}/merge/{}", src, dst))
+        .method(Method::Post)
+        .fetch()
+        .await.map_err(|e| { log!(e); })?
+        .check_status().map_err(|e| { log!(e); })?;
+    Ok(())
+}
+
 async fn new_note() -> Result<NoteId, ()> {
     let bytes = Request::new("/api/note/new")
         .method(Method::Post)
@@ -295,7 +510,9 @@ fn view(model: &Model) -> Node<Msg> {
             IF![
                 model.tag_tree.is_some() && model.sidebar_tab == SidebarTab::TagTree =>
                 div![
-                    tag_tree_view(model.tag_tree.as_ref().unwrap(), &model.tag_tree_folds, &model.notes, &model.current_note),
+                    drag_ev(Ev::DragOver, |event| { event.prevent_default(); None::<Msg> }),
+                    drag_ev(Ev::Drop, |event| { event.prevent_default(); Msg::DropTag(None) }),
+                    tag_tree_view(model.tag_tree.as_ref().unwrap(), &ROOT_TAG, &model.tag_tree_folds, &model.notes, &model.current_note),
                     input![
                         C!["w-full"],
                         attrs!{
@@ -314,6 +531,16 @@ fn view(model: &Model) -> Node<Msg> {
                                 None
                             }
                         })),
+                    ],
+                    label![
+                        input![
+                            attrs!{
+                                At::Type => "checkbox",
+                                At::Checked => model.show_hidden.as_at_value(),
+                            },
+                            ev(Ev::Change, |_| Msg::ToggleShowHidden),
+                        ],
+                        " show archived",
                     ]
                 ]
             ],
@@ -325,6 +552,7 @@ fn view(model: &Model) -> Node<Msg> {
         div![
             C!["h-screen", "flex", "flex-col", "flex-grow", "p-4", "bg-gray-100"],
             div![IF![model.current_note.is_some() => note_title_view(&model)]],
+            IF![model.conflict.is_some() => conflict_view(&model)],
             div![
                 C!["flex-grow", "overflow-y-auto"],
                 id!["editor"],
@@ -335,12 +563,15 @@ fn view(model: &Model) -> Node<Msg> {
             C!["h-screen", "overflow-y-auto", "top-0", "sticky", "p-4", "bg-gray-500"],
             IF![model.current_note.is_some() && model.tag_tree.is_some() => div![
                 div![C!["text-2xl"], "tags"],
-                get_tags_for_note(&model.tag_tree.as_ref().unwrap(), &model.current_note.unwrap()).iter().map(| tag | {
+                {
+                    let tag_tree = model.tag_tree.as_ref().unwrap();
+                    tag_tree.canonicalize_tags(tag_tree.tags_for_note(&model.current_note.unwrap()))
+                }.iter().map(| tag | {
                     div![
                         C!["tagbubble"],
                         div![
                             C!["tagbubble-inner"],
-                            get_tag_name(&model.tag_tree.as_ref().unwrap(), &tag).unwrap().iter().map(|part| div![part]),
+                            model.tag_tree.as_ref().unwrap().tag_name(&tag).unwrap().iter().map(|part| div![part]),
                         ],
                         button![
                             C!["ml-auto"],
@@ -368,7 +599,7 @@ fn view(model: &Model) -> Node<Msg> {
                         if event.key() == "Enter" {
                             let target = event.current_target().unwrap();
                             let input_elem = to_input(&target);
-                            let tag = get_tag_by_full_name(&tag_tree.unwrap(), input_elem.value().split(">").collect());
+                            let tag = tag_tree.unwrap().tag_by_full_name(&input_elem.value().split(">").collect::<Vec<_>>());
                             input_elem.set_value("");
 
                             if let Some(note) = note {
@@ -379,28 +610,83 @@ fn view(model: &Model) -> Node<Msg> {
                         }
                         None
                     })),
-                ]
+                ],
+                IF![!model.annotations.is_empty() => div![
+                    div![C!["text-2xl", "mt-4"], "annotations"],
+                    ul![
+                        model.annotations.iter().filter_map(|link| {
+                            model.notes.get(&link.from).map(|note| li![
+                                C!["note", "flex"],
+                                button![
+                                    C!["focus:outline-none"],
+                                    note.name.as_str(),
+                                    ev(Ev::Click, enc!((link.from => from) move |_| Msg::OpenNote(from))),
+                                ],
+                                link.to_anchor.as_ref().map(|anchor| button![
+                                    C!["focus:outline-none", "ml-auto"],
+                                    anchor.to_string(),
+                                    ev(Ev::Click, enc!((model.current_note => note, anchor) move |_| {
+                                        note.map(|note| Msg::FollowAnchor((note, anchor)))
+                                    })),
+                                ]),
+                            ])
+                        }),
+                    ],
+                ]],
+                button![
+                    C!["w-full", "mt-4", "border", "border-black"],
+                    "archive note",
+                    ev(Ev::Click, enc!((model.current_note => note) move |_| note.map(Msg::ArchiveNote))),
+                ],
+                button![
+                    C!["w-full", "mt-4", "border", "border-black"],
+                    "delete note",
+                    ev(Ev::Click, enc!((model.current_note => note) move |_| note.map(Msg::DeleteNote))),
+                ],
             ]]
         ],
     ]
 }
 
-fn tag_tree_view(tag_tree: &Vec<TagTree>, tag_tree_folds: &HashMap<TagId, bool>, notes: &HashMap<NoteId, Note>, current_note: &Option<NoteId>) -> Node<Msg> {
+fn tag_tree_view(tag_tree: &TagForest, parent: &TagId, tag_tree_folds: &HashMap<TagId, bool>, notes: &HashMap<NoteId, Note>, current_note: &Option<NoteId>) -> Node<Msg> {
     ul![
         {
-            let mut tag_tree = tag_tree.clone();
-            tag_tree.sort_by(|a, b| a.name.cmp(&b.name));
+            let mut children = tag_tree.children(parent);
+            children.sort_by(|a, b| a.name.cmp(&b.name));
             
-            tag_tree.iter().map(|tag| {
+            children.iter().map(|tag| {
                 li![
                     IF![!tag_tree_folds.get(&tag.id).unwrap_or(&false) => C!["tree-closed"]],
                     div![
                         C!["flex", "tagtree-row"],
+                        attrs!{
+                            At::Draggable => true,
+                            At::Title => "Drag onto another tag to move it there, or shift-drag to merge it",
+                        },
+                        drag_ev(Ev::DragStart, enc!((&tag.id => id) move |event| {
+                            event.stop_propagation();
+                            Msg::DragTag(id)
+                        })),
+                        drag_ev(Ev::DragOver, |event| { event.prevent_default(); None::<Msg> }),
+                        drag_ev(Ev::Drop, enc!((&tag.id => id) move |event| {
+                            event.prevent_default();
+                            event.stop_propagation();
+                            if event.shift_key() {
+                                Msg::MergeDroppedTag(id)
+                            } else {
+                                Msg::DropTag(Some(id))
+                            }
+                        })),
                         button![
                             C!["focus:outline-none"],
                             &tag.name,
                             ev(Ev::Click, enc!((&tag.id => id) move |_| Msg::ToggleTag(id))),
-                            ev(Ev::DblClick, enc!((&tag.id => id) move |_| log!("dblclick", id))),
+                            ev(Ev::DblClick, enc!((&tag.id => id, &tag.name => name) move |_| {
+                                match window().prompt_with_message_and_default("Rename tag", &name) {
+                                    Ok(Some(new_name)) if !new_name.is_empty() && new_name != name => Some(Msg::RenameTag((id, new_name))),
+                                    _ => None,
+                                }
+                            })),
                         ],
                         button![
                             C!["focus:outline-none", "ml-auto", "hidden"],
@@ -408,7 +694,7 @@ fn tag_tree_view(tag_tree: &Vec<TagTree>, tag_tree_folds: &HashMap<TagId, bool>,
                             ev(Ev::Click, enc!((&tag.id => id) move |_| Msg::DeleteTag(id))),
                         ]
                     ],
-                    tag_tree_view(&tag.children, tag_tree_folds, notes, current_note),
+                    tag_tree_view(tag_tree, &tag.id, tag_tree_folds, notes, current_note),
                     ul![
                         {
                             let mut tag_notes = tag.notes.clone();
@@ -470,6 +756,39 @@ fn note_title_view(model: &Model) -> Node<Msg> {
     ]
 }
 
+// Shows both sides of the current note's conflict next to each other, to pick one of them. The
+// editor can also be used to merge them by hand.
+fn conflict_view(model: &Model) -> Node<Msg> {
+    let (conflict, ours, theirs) = model.conflict.as_ref().unwrap();
+    let note = conflict.note_id;
+    let version = |title: &str, text: &str, hash: [u8; 32]| div![
+        C!["w-1/2", "p-2"],
+        div![C!["font-bold"], title],
+        pre![C!["whitespace-pre-wrap", "overflow-x-auto"], text],
+        button![
+            C!["w-full", "mt-2", "border", "border-black"],
+            "keep this version",
+            ev(Ev::Click, move |_| Msg::ResolveConflict((note, Resolution::Keep(hash)))),
+        ],
+    ];
+    div![
+        C!["mb-4", "p-2", "bg-yellow-200", "border", "border-black"],
+        div!["This note was changed on another device, and the changes couldn't be merged with the ones made here."],
+        div![
+            C!["flex"],
+            version("this device", ours, conflict.ours),
+            version("other device", theirs, conflict.theirs),
+        ],
+        IF![model.note_dirty => button![
+            C!["w-full", "mt-2", "border", "border-black"],
+            "keep what's in the editor",
+            ev(Ev::Click, enc!((model.note_text => text) move |_| {
+                text.map(|text| Msg::ResolveConflict((note, Resolution::Merged(text.into_bytes()))))
+            })),
+        ]],
+    ]
+}
+
 #[wasm_bindgen]
 pub fn start() -> Box<[JsValue]> {
     let app = App::start("app", init, update, view);
@@ -500,4 +819,5 @@ extern "C" {
     fn start_slate();
     #[wasm_bindgen(js_namespace = window)]
     fn update_slate(_: &str);
+    fn scroll_slate_to_line(_: u32);
 }
@@ -41,3 +41,11 @@ const App = () => {
 function start_slate() {
 	ReactDOM.render(React.createElement(App), document.getElementById("editor"));
 }
+
+// Every line of the note is its own element, see deserialize.
+function scroll_slate_to_line(line) {
+	const lines = document.querySelectorAll('#editor [data-slate-node="element"]');
+	if (lines[line]) {
+		lines[line].scrollIntoView();
+	}
+}
@@ -1,5 +1,5 @@
 use rouille::{Request, Response};
-use libcommonplace::{NoteId, TagId, Note, add_note, open_db, get_all_notes, get_untagged_notes, get_tag_tree, rename_note, update_note_bytes, tag_note_by_uuid, untag_note_by_uuid, create_tag, delete_tag_by_uuid, blobstore_get};
+use libcommonplace::{NoteId, TagId, Note, NewLink, Anchor, Grade, Resolution, Connection, CommonplaceError, add_note, open_db, find_repo, get_all_notes, get_untagged_notes, get_tag_tree, rename_note, delete_note, archive_note, unarchive_note, update_note_bytes, tag_note_by_uuid, untag_note_by_uuid, create_tag, rename_tag, move_tag, merge_tags, delete_tag_by_uuid, blobstore_get, parse_query, query_notes, search_notes, get_note_history, get_previous_contents, get_note, diff_blobs, add_link, delete_link, get_links, get_backlinks, resolve_anchor, get_note_contents, get_cards_for_note, get_due_cards, review_card, get_orphaned_cards, get_changed_cards, list_conflicts, get_conflict, resolve_conflict};
 use rust_embed::RustEmbed;
 use uuid::Uuid;
 use rusqlite::params;
@@ -7,6 +7,7 @@ use std::str::FromStr;
 use std::io::Read;
 use std::path::PathBuf;
 use std::convert::TryInto;
+use std::env;
 
 #[derive(RustEmbed)]
 #[folder = "../gui_client/static/"]
@@ -27,29 +28,33 @@ fn handle_static(path: String) -> Response {
     }
 }
 
-fn handle_show_tree() -> Response {
-    let db = open_db().unwrap();
-    let tree = get_tag_tree(&db).unwrap();
+fn handle_show_tree(db: &Connection, include_hidden: bool) -> Response {
+    let tree = get_tag_tree(db, include_hidden).unwrap();
     Response::from_data("application/json", serde_json::to_vec(&tree).unwrap())
 }
 
-fn handle_get_blob(hash: &str) -> Response {
+fn parse_hash(hash: &str) -> Option<blake3::Hash> {
     if !hash.chars().all(|c| (c >= '0' && c <= '9') || (c >= 'a' && c <= 'f'))  {
-        return Response::empty_404();
+        return None;
     }
-    let hash_bytes = hex::decode(hash).unwrap();
-    let hash_array: [u8; blake3::OUT_LEN] = hash_bytes[..].try_into().unwrap();
-    let hash: blake3::Hash = hash_array.into();
-    let db = open_db().unwrap();
-    if let Ok(contents) = blobstore_get(&db, hash) {
+    let hash_bytes = hex::decode(hash).ok()?;
+    let hash_array: [u8; blake3::OUT_LEN] = hash_bytes[..].try_into().ok()?;
+    Some(hash_array.into())
+}
+
+fn handle_get_blob(db: &Connection, hash: &str) -> Response {
+    let hash = match parse_hash(hash) {
+        Some(hash) => hash,
+        None => return Response::empty_404(),
+    };
+    if let Ok(contents) = blobstore_get(db, hash) {
         Response::from_data("application/octet-stream", contents)
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_get_note(uuid: &str) -> Response {
-    let db = open_db().unwrap();
+fn handle_get_note(db: &Connection, uuid: &str) -> Response {
     if let Ok(uuid) = Uuid::from_str(uuid) {
         let mut note_query = db.prepare("SELECT * FROM Notes WHERE id = ?1").unwrap();
         let note = note_query.query_row(params![uuid], |row| {
@@ -68,91 +73,365 @@ fn handle_get_note(uuid: &str) -> Response {
     }
 }
 
-fn handle_rename_note(name: Vec<u8>, uuid: &str) -> Response {
+// Serves the note with its own mimetype rather than as a blob, so that the browser can display it
+// and follow anchor fragments like #page=3.
+fn handle_get_note_contents(db: &Connection, uuid: &str) -> Response {
+    let uuid = match Uuid::from_str(uuid) {
+        Ok(uuid) => uuid,
+        Err(_) => return Response::empty_404(),
+    };
+    match (get_note(db, uuid), get_note_contents(db, uuid)) {
+        (Ok(note), Ok(contents)) => Response::from_data(note.mimetype, contents),
+        _ => Response::empty_404(),
+    }
+}
+
+fn handle_resolve_anchor(db: &Connection, uuid: &str, anchor: Option<String>) -> Response {
+    let uuid = match Uuid::from_str(uuid) {
+        Ok(uuid) => uuid,
+        Err(_) => return Response::empty_404(),
+    };
+    let anchor: Anchor = match anchor.unwrap_or_default().parse() {
+        Ok(anchor) => anchor,
+        Err(e) => return Response::text(e.to_string()).with_status_code(400),
+    };
+    if get_note(db, uuid).is_err() {
+        return Response::empty_404();
+    }
+    match resolve_anchor(db, uuid, &anchor) {
+        Ok(resolved) => Response::from_data("application/json", serde_json::to_vec(&resolved).unwrap()),
+        Err(_) => Response::text("the anchor doesn't fit this note").with_status_code(400),
+    }
+}
+
+fn handle_get_note_history(db: &Connection, uuid: &str) -> Response {
+    if let Ok(uuid) = Uuid::from_str(uuid) {
+        match get_note_history(db, uuid) {
+            Ok(history) => Response::from_data("application/json", serde_json::to_vec(&history).unwrap()),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+// `from` defaults to the most recent historical version with different contents, or to the current
+// version if the contents have never changed, and `to` defaults to the current version.
+fn handle_get_note_diff(db: &Connection, uuid: &str, from: Option<String>, to: Option<String>) -> Response {
+    let uuid = match Uuid::from_str(uuid) {
+        Ok(uuid) => uuid,
+        Err(_) => return Response::empty_404(),
+    };
+    let note = match get_note(db, uuid) {
+        Ok(note) => note,
+        Err(_) => return Response::empty_404(),
+    };
+    let from = match from {
+        Some(from) => parse_hash(&from),
+        None => match get_previous_contents(db, uuid) {
+            Ok(version) => Some(version.map_or(note.hash, |x| x.hash).into()),
+            Err(e) => return error_response(e),
+        },
+    };
+    let to = match to {
+        Some(to) => parse_hash(&to),
+        None => Some(note.hash.into()),
+    };
+    if let (Some(from), Some(to)) = (from, to) {
+        match diff_blobs(db, from, to) {
+            Ok(hunks) => Response::from_data("application/json", serde_json::to_vec(&hunks).unwrap()),
+            Err(_) => Response::empty_404(),
+        }
+    } else {
+        Response::empty_400()
+    }
+}
+
+fn handle_get_links(db: &Connection, uuid: &str, backlinks: bool) -> Response {
+    if let Ok(uuid) = Uuid::from_str(uuid) {
+        match if backlinks { get_backlinks(db, uuid) } else { get_links(db, uuid) } {
+            Ok(links) => Response::from_data("application/json", serde_json::to_vec(&links).unwrap()),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+// The body is a JSON NewLink.
+fn handle_add_link(db: &Connection, uuid: &str, link: Vec<u8>) -> Response {
+    let uuid = Uuid::from_str(uuid);
+    let link: Result<NewLink, _> = serde_json::from_slice(&link);
+    match (uuid, link) {
+        (Ok(uuid), Ok(link)) => match add_link(db, uuid, link) {
+            Ok(id) => Response::from_data("application/json", serde_json::to_vec(&id).unwrap()),
+            Err(CommonplaceError::InvalidAnchor) => Response::text("the anchor doesn't fit the note").with_status_code(400),
+            Err(_) => Response::empty_404(),
+        },
+        (Ok(_), Err(_)) => Response::empty_400(),
+        _ => Response::empty_404(),
+    }
+}
+
+fn handle_delete_link(db: &Connection, uuid: &str) -> Response {
+    if let Ok(uuid) = Uuid::from_str(uuid) {
+        match delete_link(db, uuid) {
+            Ok(()) => Response::empty_204(),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+fn handle_get_cards(db: &Connection, uuid: &str) -> Response {
+    if let Ok(uuid) = Uuid::from_str(uuid) {
+        match get_cards_for_note(db, uuid) {
+            Ok(cards) => Response::from_data("application/json", serde_json::to_vec(&cards).unwrap()),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+fn handle_get_due_cards(db: &Connection) -> Response {
+    match get_due_cards(db) {
+        Ok(cards) => Response::from_data("application/json", serde_json::to_vec(&cards).unwrap()),
+        Err(e) => error_response(e),
+    }
+}
+
+fn handle_get_orphaned_cards(db: &Connection) -> Response {
+    match get_orphaned_cards(db) {
+        Ok(cards) => Response::from_data("application/json", serde_json::to_vec(&cards).unwrap()),
+        Err(e) => error_response(e),
+    }
+}
+
+fn handle_get_changed_cards(db: &Connection) -> Response {
+    match get_changed_cards(db) {
+        Ok(cards) => Response::from_data("application/json", serde_json::to_vec(&cards).unwrap()),
+        Err(e) => error_response(e),
+    }
+}
+
+// The body is the JSON grade, and the response is the rescheduled card.
+fn handle_review_card(db: &Connection, uuid: &str, grade: Vec<u8>) -> Response {
+    let uuid = Uuid::from_str(uuid);
+    let grade: Result<Grade, _> = serde_json::from_slice(&grade);
+    match (uuid, grade) {
+        (Ok(uuid), Ok(grade)) => match review_card(db, uuid, grade) {
+            Ok(card) => Response::from_data("application/json", serde_json::to_vec(&card).unwrap()),
+            Err(_) => Response::empty_404(),
+        },
+        (Ok(_), Err(_)) => Response::empty_400(),
+        _ => Response::empty_404(),
+    }
+}
+
+fn handle_get_conflicts(db: &Connection) -> Response {
+    match list_conflicts(db) {
+        Ok(conflicts) => Response::from_data("application/json", serde_json::to_vec(&conflicts).unwrap()),
+        Err(e) => error_response(e),
+    }
+}
+
+fn handle_get_conflict(db: &Connection, uuid: &str) -> Response {
+    let uuid = match Uuid::from_str(uuid) {
+        Ok(uuid) => uuid,
+        Err(_) => return Response::empty_404(),
+    };
+    match get_conflict(db, uuid) {
+        Ok(conflict) => Response::from_data("application/json", serde_json::to_vec(&conflict).unwrap()),
+        Err(_) => Response::empty_404(),
+    }
+}
+
+fn handle_resolve_conflict(db: &Connection, uuid: &str, resolution: Vec<u8>) -> Response {
+    let uuid = Uuid::from_str(uuid);
+    let resolution: Result<Resolution, _> = serde_json::from_slice(&resolution);
+    match (uuid, resolution) {
+        (Ok(uuid), Ok(resolution)) => match resolve_conflict(db, uuid, resolution) {
+            Ok(()) => Response::empty_204(),
+            Err(CommonplaceError::InvalidResolution) => Response::text("that version isn't part of the conflict").with_status_code(400),
+            Err(_) => Response::empty_404(),
+        },
+        (Ok(_), Err(_)) => Response::empty_400(),
+        _ => Response::empty_404(),
+    }
+}
+
+fn handle_rename_note(db: &Connection, name: Vec<u8>, uuid: &str) -> Response {
     if let Ok(uuid) = Uuid::from_str(uuid) {
-        let db = open_db().unwrap();
-        rename_note(&db, uuid, String::from_utf8(name).unwrap());
+        rename_note(db, uuid, String::from_utf8(name).unwrap());
         Response::empty_204()
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_update_note(contents: Vec<u8>, uuid: &str) -> Response {
+fn handle_delete_note(db: &Connection, uuid: &str) -> Response {
+    if let Ok(uuid) = Uuid::from_str(uuid) {
+        match delete_note(db, uuid) {
+            Ok(()) => Response::empty_204(),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+// For errors that don't mean anything in particular to the request: things that don't exist are
+// 404s, and anything else is a problem with the repository.
+fn error_response(e: CommonplaceError) -> Response {
+    match e {
+        CommonplaceError::Sqlite(rusqlite::Error::QueryReturnedNoRows) => Response::empty_404(),
+        e => Response::text(format!("{:?}", e)).with_status_code(500),
+    }
+}
+
+fn handle_archive_note(db: &Connection, uuid: &str, archive: bool) -> Response {
     if let Ok(uuid) = Uuid::from_str(uuid) {
-        let db = open_db().unwrap();
-        update_note_bytes(&db, uuid, contents);
+        let result = if archive { archive_note(db, uuid) } else { unarchive_note(db, uuid) };
+        match result {
+            Ok(()) => Response::empty_204(),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+fn handle_update_note(db: &Connection, contents: Vec<u8>, uuid: &str) -> Response {
+    if let Ok(uuid) = Uuid::from_str(uuid) {
+        update_note_bytes(db, uuid, contents);
         Response::empty_204()
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_get_notes() -> Response {
-    let db = open_db().unwrap();
-    if let Ok(notes) = get_all_notes(&db) {
+fn handle_get_notes(db: &Connection) -> Response {
+    if let Ok(notes) = get_all_notes(db) {
         Response::from_data("application/json", serde_json::to_vec(&notes).unwrap())
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_get_untagged_notes() -> Response {
-    let db = open_db().unwrap();
-    if let Ok(notes) = get_untagged_notes(&db) {
+fn handle_get_untagged_notes(db: &Connection) -> Response {
+    if let Ok(notes) = get_untagged_notes(db) {
         Response::from_data("application/json", serde_json::to_vec(&notes).unwrap())
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_new_note() -> Response {
-    let db = open_db().unwrap();
-    if let Ok(uuid) = add_note(&db, "new_note".to_string(), PathBuf::from(r"/dev/null")) {
+fn handle_query(db: &Connection, query: Option<String>, include_hidden: bool) -> Response {
+    match parse_query(&query.unwrap_or_default()) {
+        Ok(query) => match query_notes(db, &query, include_hidden) {
+            Ok(notes) => Response::from_data("application/json", serde_json::to_vec(&notes).unwrap()),
+            Err(e) => error_response(e),
+        },
+        Err(e) => Response::text(e.to_string()).with_status_code(400),
+    }
+}
+
+fn handle_search(db: &Connection, query: Option<String>) -> Response {
+    if let Ok(hits) = search_notes(db, &query.unwrap_or_default()) {
+        Response::from_data("application/json", serde_json::to_vec(&hits).unwrap())
+    } else {
+        Response::empty_400()
+    }
+}
+
+fn handle_new_note(db: &Connection) -> Response {
+    if let Ok(uuid) = add_note(db, "new_note".to_string(), PathBuf::from(r"/dev/null")) {
         Response::from_data("application/json", serde_json::to_vec(&uuid).unwrap())
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_new_tag(tag_name: Vec<String>) -> Response {
-    let db = open_db().unwrap();
-    if let Ok(uuid) = create_tag(&db, tag_name) {
+fn handle_new_tag(db: &Connection, tag_name: Vec<String>) -> Response {
+    if let Ok(uuid) = create_tag(db, tag_name) {
         Response::empty_204()
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_note_add_tag(note_id: &str, tag_id: &str) -> Response {
-    let db = open_db().unwrap();
+fn handle_note_add_tag(db: &Connection, note_id: &str, tag_id: &str) -> Response {
     let note_id = Uuid::from_str(note_id);
     let tag_id = Uuid::from_str(tag_id);
     if let (Ok(note_id), Ok(tag_id)) = (note_id, tag_id) {
-        tag_note_by_uuid(&db, note_id, tag_id);
+        tag_note_by_uuid(db, note_id, tag_id);
         Response::empty_204()
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_note_delete_tag(note_id: &str, tag_id: &str) -> Response {
-    let db = open_db().unwrap();
+fn handle_note_delete_tag(db: &Connection, note_id: &str, tag_id: &str) -> Response {
     let note_id = Uuid::from_str(note_id);
     let tag_id = Uuid::from_str(tag_id);
     if let (Ok(note_id), Ok(tag_id)) = (note_id, tag_id) {
-        untag_note_by_uuid(&db, note_id, tag_id);
+        untag_note_by_uuid(db, note_id, tag_id);
         Response::empty_204()
     } else {
         Response::empty_404()
     }
 }
 
-fn handle_delete_tag(tag_id: &str) -> Response {
-    let db = open_db().unwrap();
+fn handle_rename_tag(db: &Connection, tag_id: &str, name: Vec<u8>) -> Response {
+    let tag_id = Uuid::from_str(tag_id);
+    let name = String::from_utf8(name);
+    if let (Ok(tag_id), Ok(name)) = (tag_id, name) {
+        match rename_tag(db, tag_id, name) {
+            Ok(()) => Response::empty_204(),
+            Err(CommonplaceError::TagNameTaken) => Response::text("a tag with that name already exists").with_status_code(409),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+// The body is the JSON parent tag id, or null to move the tag to the root.
+fn handle_move_tag(db: &Connection, tag_id: &str, parent: Vec<u8>) -> Response {
+    let tag_id = Uuid::from_str(tag_id);
+    let parent: Result<Option<TagId>, _> = serde_json::from_slice(&parent);
+    if let (Ok(tag_id), Ok(parent)) = (tag_id, parent) {
+        match move_tag(db, tag_id, parent.map(|x| x.uuid)) {
+            Ok(()) => Response::empty_204(),
+            Err(CommonplaceError::TagNameTaken) => Response::text("a tag with that name already exists there").with_status_code(409),
+            Err(CommonplaceError::TagCycle) => Response::text("can't move a tag under itself").with_status_code(409),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+fn handle_merge_tag(db: &Connection, src: &str, dst: &str) -> Response {
+    let src = Uuid::from_str(src);
+    let dst = Uuid::from_str(dst);
+    if let (Ok(src), Ok(dst)) = (src, dst) {
+        match merge_tags(db, src, dst) {
+            Ok(()) => Response::empty_204(),
+            Err(CommonplaceError::TagCycle) => Response::text("can't merge a tag into its own descendant").with_status_code(409),
+            Err(e) => error_response(e),
+        }
+    } else {
+        Response::empty_404()
+    }
+}
+
+fn handle_delete_tag(db: &Connection, tag_id: &str) -> Response {
     let tag_id = Uuid::from_str(tag_id);
     if let Ok(tag_id) = tag_id {
-        delete_tag_by_uuid(&db, tag_id);
+        delete_tag_by_uuid(db, tag_id);
         Response::empty_204()
     } else {
         Response::empty_404()
@@ -163,40 +442,98 @@ fn handle_delete_tag(tag_id: &str) -> Response {
 extern crate rouille;
 
 fn main() {
+    let mut repo = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repo" => repo = args.next(),
+            _ => {
+                eprintln!("usage: commonplace-gui [--repo REPO]");
+                std::process::exit(1);
+            },
+        }
+    }
+    let repo = find_repo(repo.as_deref()).unwrap();
+
     rouille::start_server("localhost:38841", move |request| {
+        let db = open_db(&repo).unwrap();
         let url = request.url();
         let path: Vec<&str> = url.split("/").filter(|x| *x != "").collect();
         match (request.method(), &path[..]) {
-            ("GET", &["api", "showtree"]) => handle_show_tree(),
-            ("GET", &["api", "notes"]) => handle_get_notes(),
-            ("GET", &["api", "notes", "untagged"]) => handle_get_untagged_notes(),
-            ("GET", &["api", "blob", hash]) => handle_get_blob(hash),
-            ("GET", &["api", "note", uuid]) => handle_get_note(uuid),
+            ("GET", &["api", "showtree"]) => handle_show_tree(&db, request.get_param("hidden").is_some()),
+            ("GET", &["api", "notes"]) => handle_get_notes(&db),
+            ("GET", &["api", "notes", "untagged"]) => handle_get_untagged_notes(&db),
+            ("GET", &["api", "query"]) => handle_query(&db, request.get_param("q"), request.get_param("hidden").is_some()),
+            ("GET", &["api", "search"]) => handle_search(&db, request.get_param("q")),
+            ("GET", &["api", "blob", hash]) => handle_get_blob(&db, hash),
+            ("GET", &["api", "note", uuid]) => handle_get_note(&db, uuid),
+            ("GET", &["api", "note", uuid, "contents"]) => handle_get_note_contents(&db, uuid),
+            ("GET", &["api", "note", uuid, "resolve"]) => handle_resolve_anchor(&db, uuid, request.get_param("anchor")),
+            ("GET", &["api", "note", uuid, "cards"]) => handle_get_cards(&db, uuid),
+            ("GET", &["api", "review"]) => handle_get_due_cards(&db),
+            ("GET", &["api", "cards", "orphaned"]) => handle_get_orphaned_cards(&db),
+            ("GET", &["api", "cards", "changed"]) => handle_get_changed_cards(&db),
+            ("GET", &["api", "conflicts"]) => handle_get_conflicts(&db),
+            ("GET", &["api", "conflicts", uuid]) => handle_get_conflict(&db, uuid),
+            ("GET", &["api", "note", uuid, "history"]) => handle_get_note_history(&db, uuid),
+            ("GET", &["api", "note", uuid, "diff"]) => handle_get_note_diff(&db, uuid, request.get_param("from"), request.get_param("to")),
+            ("GET", &["api", "note", uuid, "links"]) => handle_get_links(&db, uuid, false),
+            ("GET", &["api", "note", uuid, "backlinks"]) => handle_get_links(&db, uuid, true),
             ("GET", path) => handle_static(path.join("/")),
             ("POST", &["api", "tag", "new"]) => {
                 let mut body = vec![];
                 request.data().unwrap().read_to_end(&mut body);
                 let tag_name = serde_json::from_slice(&body);
                 if let Ok(tag_name) = tag_name {
-                    handle_new_tag(tag_name)
+                    handle_new_tag(&db, tag_name)
                 } else {
                     Response::empty_404()
                 }
             },
-            ("POST", &["api", "note", "new"]) => handle_new_note(),
-            ("POST", &["api", "note", note_id, "tag", tag_id]) => handle_note_add_tag(note_id, tag_id),
+            ("POST", &["api", "tag", tag_id, "rename"]) => {
+                let mut body = vec![];
+                request.data().unwrap().read_to_end(&mut body);
+                handle_rename_tag(&db, tag_id, body)
+            },
+            ("POST", &["api", "tag", tag_id, "move"]) => {
+                let mut body = vec![];
+                request.data().unwrap().read_to_end(&mut body);
+                handle_move_tag(&db, tag_id, body)
+            },
+            ("POST", &["api", "tag", src, "merge", dst]) => handle_merge_tag(&db, src, dst),
+            ("POST", &["api", "review", card_id]) => {
+                let mut body = vec![];
+                request.data().unwrap().read_to_end(&mut body);
+                handle_review_card(&db, card_id, body)
+            },
+            ("POST", &["api", "conflicts", uuid]) => {
+                let mut body = vec![];
+                request.data().unwrap().read_to_end(&mut body);
+                handle_resolve_conflict(&db, uuid, body)
+            },
+            ("POST", &["api", "note", "new"]) => handle_new_note(&db),
+            ("POST", &["api", "note", note_id, "tag", tag_id]) => handle_note_add_tag(&db, note_id, tag_id),
+            ("POST", &["api", "note", uuid, "archive"]) => handle_archive_note(&db, uuid, true),
+            ("POST", &["api", "note", uuid, "links"]) => {
+                let mut body = vec![];
+                request.data().unwrap().read_to_end(&mut body);
+                handle_add_link(&db, uuid, body)
+            },
             ("POST", &["api", "note", uuid, "rename"]) => {
                 let mut body = vec![];
                 request.data().unwrap().read_to_end(&mut body);
-                handle_rename_note(body, uuid)
+                handle_rename_note(&db, body, uuid)
             },
             ("POST", &["api", "note", uuid]) => {
                 let mut body = vec![];
                 request.data().unwrap().read_to_end(&mut body);
-                handle_update_note(body, uuid)
+                handle_update_note(&db, body, uuid)
             },
-            ("DELETE", &["api", "note", note_id, "tag", tag_id]) => handle_note_delete_tag(note_id, tag_id),
-            ("DELETE", &["api", "tag", tag_id]) => handle_delete_tag(tag_id),
+            ("DELETE", &["api", "note", note_id, "tag", tag_id]) => handle_note_delete_tag(&db, note_id, tag_id),
+            ("DELETE", &["api", "note", uuid, "archive"]) => handle_archive_note(&db, uuid, false),
+            ("DELETE", &["api", "note", uuid]) => handle_delete_note(&db, uuid),
+            ("DELETE", &["api", "tag", tag_id]) => handle_delete_tag(&db, tag_id),
+            ("DELETE", &["api", "link", uuid]) => handle_delete_link(&db, uuid),
 
             _ => rouille::Response::empty_404()
         }
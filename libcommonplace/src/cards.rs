@@ -0,0 +1,267 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::params;
+use uuid::Uuid;
+use crate::{Connection, CommonplaceError, Card, CardId, CardText, ChangedCard, Grade, NoteId, Operation, Review, Schedule, extract_cards, card_similarity, get_all_notes, get_note, get_note_contents, tick, log_operation};
+
+const DAY: i64 = 24 * 60 * 60;
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn card_from_row(row: &rusqlite::Row) -> Result<Card, rusqlite::Error> {
+    Ok(Card {
+        id: CardId { uuid: row.get("id")? },
+        note_id: NoteId { uuid: row.get("note_id")? },
+        front: row.get("front")?,
+        back: row.get("back")?,
+        schedule: Schedule {
+            ease: row.get("ease")?,
+            interval: row.get("interval")?,
+            repetitions: row.get("repetitions")?,
+        },
+        due: row.get("due")?,
+        orphaned: row.get("orphaned")?,
+    })
+}
+
+fn review_from_row(row: &rusqlite::Row) -> Result<Review, rusqlite::Error> {
+    Ok(Review {
+        time: row.get("time")?,
+        quality: row.get("grade")?,
+        ease: row.get("ease")?,
+        interval: row.get("interval")?,
+        front: row.get("front")?,
+        back: row.get("back")?,
+    })
+}
+
+// Edited cards are matched up with the card they used to be if at least this similar.
+const SAME_CARD_SIMILARITY: f64 = 0.5;
+
+fn insert_card(db: &Connection, note: Uuid, text: &CardText, now: i64) -> Result<(), CommonplaceError> {
+    let schedule = Schedule::default();
+    db.execute(
+        "INSERT INTO Cards (id, note_id, front, back, ease, interval, repetitions, due) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![Uuid::new_v4(), note, text.front, text.back, schedule.ease, schedule.interval, schedule.repetitions, now]
+    )?;
+    Ok(())
+}
+
+// Re-extracts the cards from a note after its contents change, keeping track of which card is
+// which so that cards keep their schedule and reviews when the note around them is edited:
+//
+// * Cards with exactly the same text as before are the same card.
+// * Of the rest, each new card is matched with the most similar old card, as long as they're at
+//   least SAME_CARD_SIMILARITY alike, and the old card's text is updated.
+// * New cards that are left over are due immediately, and old cards that are left over are
+//   orphaned. Orphaned cards are matched like any others, so they come back if their text does.
+pub fn sync_cards(db: &Connection, note: Uuid) -> Result<(), CommonplaceError> {
+    let note = get_note(db, note)?;
+    let texts = if note.mimetype.starts_with("text/") {
+        extract_cards(&String::from_utf8_lossy(&get_note_contents(db, note.id)?))
+    } else {
+        vec![]
+    };
+
+    let mut old = get_cards_for_note(db, note.id)?;
+    let mut new = vec![];
+    for text in texts {
+        match old.iter().position(|card| card.text() == text) {
+            Some(i) => {
+                let card = old.remove(i);
+                if card.orphaned {
+                    db.execute("UPDATE Cards SET orphaned = 0 WHERE id = ?1", params![card.id.uuid])?;
+                }
+            },
+            None => new.push(text),
+        }
+    }
+
+    let mut pairs = vec![];
+    for (i, text) in new.iter().enumerate() {
+        for (j, card) in old.iter().enumerate() {
+            let similarity = card_similarity(text, &card.text());
+            if similarity >= SAME_CARD_SIMILARITY {
+                pairs.push((similarity, i, j));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    let mut new_matches: Vec<Option<usize>> = vec![None; new.len()];
+    let mut old_matched = vec![false; old.len()];
+    for (_, i, j) in pairs {
+        if new_matches[i].is_none() && !old_matched[j] {
+            new_matches[i] = Some(j);
+            old_matched[j] = true;
+        }
+    }
+
+    let now = now();
+    for (text, matched) in new.iter().zip(new_matches) {
+        match matched {
+            Some(j) => {
+                db.execute(
+                    "UPDATE Cards SET front = ?1, back = ?2, orphaned = 0 WHERE id = ?3",
+                    params![text.front, text.back, old[j].id.uuid]
+                )?;
+            },
+            None => insert_card(db, note.id, text, now)?,
+        }
+    }
+    for (card, matched) in old.iter().zip(old_matched) {
+        if !matched && !card.orphaned {
+            db.execute("UPDATE Cards SET orphaned = 1 WHERE id = ?1", params![card.id.uuid])?;
+        }
+    }
+    Ok(())
+}
+
+pub fn sync_all_cards(db: &Connection) -> Result<(), CommonplaceError> {
+    for note in get_all_notes(db)?.keys() {
+        sync_cards(db, *note)?;
+    }
+    Ok(())
+}
+
+pub fn get_card(db: &Connection, card: Uuid) -> Result<Card, CommonplaceError> {
+    Ok(db.query_row("SELECT * FROM Cards WHERE id = ?1", params![card], card_from_row)?)
+}
+
+pub fn get_cards_for_note(db: &Connection, note: Uuid) -> Result<Vec<Card>, CommonplaceError> {
+    let mut query = db.prepare("SELECT * FROM Cards WHERE note_id = ?1 ORDER BY rowid")?;
+    let cards = query.query_map(params![note], card_from_row)?.collect::<Result<Vec<Card>, rusqlite::Error>>()?;
+    Ok(cards)
+}
+
+// Cards that are due now, most overdue first.
+pub fn get_due_cards(db: &Connection) -> Result<Vec<Card>, CommonplaceError> {
+    let mut query = db.prepare("SELECT * FROM Cards WHERE due <= ?1 AND NOT orphaned ORDER BY due, rowid")?;
+    let cards = query.query_map(params![now()], card_from_row)?.collect::<Result<Vec<Card>, rusqlite::Error>>()?;
+    Ok(cards)
+}
+
+// Records a review of the card, and reschedules it with SM-2.
+pub fn review_card(db: &Connection, card: Uuid, grade: Grade) -> Result<Card, CommonplaceError> {
+    let mut card = get_card(db, card)?;
+    let now = now();
+    let from = card.schedule;
+    card.schedule = card.schedule.next(grade);
+    card.due = now + card.schedule.interval as i64 * DAY;
+
+    let tx = db.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE Cards SET ease = ?1, interval = ?2, repetitions = ?3, due = ?4 WHERE id = ?5",
+        params![card.schedule.ease, card.schedule.interval, card.schedule.repetitions, card.due, card.id.uuid]
+    )?;
+    tx.execute(
+        "INSERT INTO ReviewLog (card_id, time, grade, ease, interval, front, back) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![card.id.uuid, now, grade.quality(), card.schedule.ease, card.schedule.interval, card.front, card.back]
+    )?;
+    let operation = Operation::ReviewCard { card: card.id, quality: grade.quality(), from, to: card.schedule };
+    log_operation(&tx, &tick(&tx)?, &operation)?;
+    tx.commit()?;
+
+    Ok(card)
+}
+
+pub fn get_orphaned_cards(db: &Connection) -> Result<Vec<Card>, CommonplaceError> {
+    let mut query = db.prepare("SELECT * FROM Cards WHERE orphaned ORDER BY note_id, rowid")?;
+    let cards = query.query_map(params![], card_from_row)?.collect::<Result<Vec<Card>, rusqlite::Error>>()?;
+    Ok(cards)
+}
+
+// Cards whose text is different from what it was at their most recent review. Cards that have
+// never been reviewed haven't changed since, so they're left out.
+pub fn get_changed_cards(db: &Connection) -> Result<Vec<ChangedCard>, CommonplaceError> {
+    let mut query = db.prepare(
+        "SELECT Cards.*, ReviewLog.front AS reviewed_front, ReviewLog.back AS reviewed_back, ReviewLog.time AS reviewed_time
+         FROM Cards JOIN ReviewLog ON ReviewLog.rowid = (
+             SELECT rowid FROM ReviewLog WHERE card_id = Cards.id ORDER BY time DESC, rowid DESC LIMIT 1
+         )
+         WHERE ReviewLog.front != Cards.front OR ReviewLog.back != Cards.back
+         ORDER BY Cards.note_id, Cards.rowid"
+    )?;
+    let cards = query.query_map(params![], |row| {
+        Ok(ChangedCard {
+            card: card_from_row(row)?,
+            reviewed: CardText { front: row.get("reviewed_front")?, back: row.get("reviewed_back")? },
+            reviewed_time: row.get("reviewed_time")?,
+        })
+    })?.collect::<Result<Vec<ChangedCard>, rusqlite::Error>>()?;
+    Ok(cards)
+}
+
+// Deletes a card and its reviews, for cleaning up orphaned cards. Cards that are still in their
+// note will come back the next time the note changes.
+pub fn delete_card(db: &Connection, card: Uuid) -> Result<(), CommonplaceError> {
+    let card = get_card(db, card)?;
+    let reviews = {
+        let mut query = db.prepare("SELECT * FROM ReviewLog WHERE card_id = ?1 ORDER BY time, rowid")?;
+        let reviews = query.query_map(params![card.id.uuid], review_from_row)?.collect::<Result<Vec<Review>, rusqlite::Error>>()?;
+        reviews
+    };
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM ReviewLog WHERE card_id = ?1", params![card.id.uuid])?;
+    tx.execute("DELETE FROM Cards WHERE id = ?1", params![card.id.uuid])?;
+    log_operation(&tx, &tick(&tx)?, &Operation::DeleteCard { card, reviews })?;
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init_memex, add_note, get_operation_log};
+
+    fn repo_with_note(contents: &str) -> (Connection, Uuid) {
+        let db = Connection::open_in_memory().unwrap();
+        init_memex(&db).unwrap();
+        let path = std::env::temp_dir().join(format!("commonplace-test-{}.md", Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        let note = add_note(&db, "cards.md".to_string(), path.clone());
+        std::fs::remove_file(&path).unwrap();
+        (db, note.unwrap())
+    }
+
+    #[test]
+    fn new_cards_are_due() {
+        let (db, note) = repo_with_note("Q: Question?\nA: Answer\n\nA {{cloze}}.\n");
+        let cards = get_cards_for_note(&db, note).unwrap();
+        assert_eq!(cards.iter().map(|x| x.text()).collect::<Vec<_>>(), extract_cards("Q: Question?\nA: Answer\n\nA {{cloze}}.\n"));
+        assert_eq!(get_due_cards(&db).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reviews_reschedule() {
+        let (db, note) = repo_with_note("Q: Question?\nA: Answer\n");
+        let card = get_cards_for_note(&db, note).unwrap().remove(0);
+
+        let reviewed = review_card(&db, card.id.uuid, Grade::Good).unwrap();
+        assert_eq!(reviewed.schedule, Schedule::default().next(Grade::Good));
+        assert!((reviewed.due - now() - DAY).abs() <= 1);
+        assert_eq!(get_card(&db, card.id.uuid).unwrap().schedule, reviewed.schedule);
+        assert!(get_due_cards(&db).unwrap().is_empty());
+
+        let reviews: i64 = db.query_row("SELECT COUNT(*) FROM ReviewLog WHERE card_id = ?1", params![card.id.uuid], |row| row.get(0)).unwrap();
+        assert_eq!(reviews, 1);
+    }
+
+    #[test]
+    fn deleting_a_card_logs_its_reviews() {
+        let (db, note) = repo_with_note("Q: Question?\nA: Answer\n");
+        let card = get_cards_for_note(&db, note).unwrap().remove(0);
+        review_card(&db, card.id.uuid, Grade::Hard).unwrap();
+        delete_card(&db, card.id.uuid).unwrap();
+        assert!(get_cards_for_note(&db, note).unwrap().is_empty());
+
+        match &get_operation_log(&db, Some(note)).unwrap().last().unwrap().operation {
+            Operation::DeleteCard { card: deleted, reviews } => {
+                assert_eq!(deleted.id, card.id);
+                assert_eq!(reviews.len(), 1);
+                assert_eq!(reviews[0].quality, Grade::Hard.quality());
+            },
+            operation => panic!("logged {:?}", operation),
+        }
+    }
+}
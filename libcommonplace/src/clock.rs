@@ -0,0 +1,44 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::params;
+use uuid::Uuid;
+use crate::{Connection, CommonplaceError, Timestamp};
+
+// Every change to notes, tags and tag maps is stamped with a Timestamp from this clock. The clock
+// never goes backwards, even if the wall clock does, and importing a bundle moves it past every
+// timestamp in the bundle, so a change made after seeing another device's change is always ordered
+// after it, however far apart the two devices' wall clocks are.
+//
+// The device id is made up when the repository is created, so copying a repository file makes two
+// repositories with the same device id. Their changes still sync, but ties between them are broken
+// arbitrarily.
+
+fn wall_clock() -> Timestamp {
+    Timestamp::from_millis(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64)
+}
+
+pub(crate) fn init_clock(db: &Connection) -> Result<(), CommonplaceError> {
+    db.execute("INSERT INTO Clock (device, hlc) VALUES (?1, 0)", params![Uuid::new_v4()])?;
+    Ok(())
+}
+
+pub fn get_device_id(db: &Connection) -> Result<Uuid, CommonplaceError> {
+    Ok(db.query_row("SELECT device FROM Clock", params![], |row| row.get(0))?)
+}
+
+// The timestamp for a change that's about to be made.
+pub fn tick(db: &Connection) -> Result<Timestamp, CommonplaceError> {
+    let (device, last): (Uuid, i64) = db.query_row("SELECT device, hlc FROM Clock", params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let hlc = wall_clock().hlc.max(last + 1);
+    db.execute("UPDATE Clock SET hlc = ?1", params![hlc])?;
+    Ok(Timestamp { hlc, device: Some(device) })
+}
+
+// Moves the clock past a timestamp from another device.
+pub(crate) fn observe(db: &Connection, hlc: i64) -> Result<(), CommonplaceError> {
+    db.execute("UPDATE Clock SET hlc = MAX(hlc, ?1)", params![hlc])?;
+    Ok(())
+}
+
+pub(crate) fn timestamp_from_row(row: &rusqlite::Row) -> Result<Timestamp, rusqlite::Error> {
+    Ok(Timestamp { hlc: row.get("hlc")?, device: row.get("device")? })
+}
@@ -0,0 +1,50 @@
+use rusqlite::params;
+use uuid::Uuid;
+use crate::{Connection, CommonplaceError, Conflict, NoteId, Operation, Resolution, add_bytes_to_blobstore, get_note, index_note_contents, sync_cards, tick, log_operation, hash_from_row};
+
+// Conflicts are recorded by import_bundle, when a note was changed on both sides and the changes
+// couldn't be merged.
+
+fn conflict_from_row(row: &rusqlite::Row) -> Result<Conflict, rusqlite::Error> {
+    Ok(Conflict {
+        note_id: NoteId { uuid: row.get("note_id")? },
+        base: hash_from_row(row, "base")?,
+        ours: hash_from_row(row, "ours")?,
+        theirs: hash_from_row(row, "theirs")?,
+        time: row.get("time")?,
+    })
+}
+
+pub fn list_conflicts(db: &Connection) -> Result<Vec<Conflict>, CommonplaceError> {
+    let mut query = db.prepare("SELECT * FROM Conflicts ORDER BY time, rowid")?;
+    let conflicts = query.query_map(params![], conflict_from_row)?.collect::<Result<Vec<Conflict>, rusqlite::Error>>()?;
+    Ok(conflicts)
+}
+
+pub fn get_conflict(db: &Connection, note: Uuid) -> Result<Conflict, CommonplaceError> {
+    Ok(db.query_row("SELECT * FROM Conflicts WHERE note_id = ?1", params![note], conflict_from_row)?)
+}
+
+// Makes the resolution the current version of the note, and forgets the conflict. The heads that
+// weren't chosen are still in the note's history.
+pub fn resolve_conflict(db: &Connection, note: Uuid, resolution: Resolution) -> Result<(), CommonplaceError> {
+    let conflict = get_conflict(db, note)?;
+
+    let tx = db.unchecked_transaction()?;
+    let hash = match resolution {
+        Resolution::Keep(hash) if [conflict.base, conflict.ours, conflict.theirs].contains(&hash) => hash.to_vec(),
+        Resolution::Keep(_) => return Err(CommonplaceError::InvalidResolution),
+        Resolution::Merged(contents) => add_bytes_to_blobstore(&tx, contents)?.as_bytes().to_vec(),
+    };
+    let old = get_note(&tx, note)?;
+    tx.execute("UPDATE Notes SET hash = ?1 WHERE id = ?2 AND hash != ?1", params![hash, note])?;
+    // Even if the head stays the same, so that importing it clears the other side's conflict.
+    let now = tick(&tx)?;
+    tx.execute("UPDATE Notes SET hlc = ?1, device = ?2 WHERE id = ?3", params![now.hlc, now.device, note])?;
+    log_operation(&tx, &now, &Operation::ResolveConflict { note: conflict.note_id, from: old.hash, to: get_note(&tx, note)?.hash })?;
+    tx.execute("DELETE FROM Conflicts WHERE note_id = ?1", params![note])?;
+    index_note_contents(&tx, note)?;
+    sync_cards(&tx, note)?;
+    tx.commit()?;
+    Ok(())
+}
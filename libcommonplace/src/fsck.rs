@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use rusqlite::{params, OptionalExtension};
+use uuid::Uuid;
+use crate::{Connection, CommonplaceError, NoteId, Operation, TagId, tick, log_operation, link_from_row, hash_from_row};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // A hash that isn't a 32 byte blob. Rows with one aren't checked for anything else.
+    MalformedHash { table: &'static str, hash: Vec<u8> },
+    // The blob's contents don't hash to the hash it's stored under.
+    HashMismatch { hash: [u8; 32], actual: [u8; 32] },
+    MissingNoteBlob { note: Uuid, hash: [u8; 32] },
+    MissingHistoryBlob { note: Uuid, hash: [u8; 32], time: i64 },
+    TagMapMissingNote { note: Uuid, tag: Uuid },
+    TagMapMissingTag { note: Uuid, tag: Uuid },
+    MissingTagParent { tag: Uuid, parent: Uuid },
+    LinkMissingNote { link: Uuid, note: Uuid },
+    TagCycle(Vec<Uuid>),
+}
+
+impl Problem {
+    // Whether repair_repository can fix this without losing any information.
+    pub fn is_repairable(&self) -> bool {
+        matches!(self, Problem::TagMapMissingNote { .. } | Problem::TagMapMissingTag { .. } | Problem::LinkMissingNote { .. })
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::MalformedHash { table, hash } => write!(f, "{} has a {} byte hash {}", table, hash.len(), hex::encode(hash)),
+            Problem::HashMismatch { hash, actual } => write!(f, "blob {} has contents with hash {}", hex::encode(hash), hex::encode(actual)),
+            Problem::MissingNoteBlob { note, hash } => write!(f, "note {} points at missing blob {}", note, hex::encode(hash)),
+            Problem::MissingHistoryBlob { note, hash, time } => write!(f, "history of note {} at {} points at missing blob {}", note, time, hex::encode(hash)),
+            Problem::TagMapMissingNote { note, tag } => write!(f, "tag {} is applied to missing note {}", tag, note),
+            Problem::TagMapMissingTag { note, tag } => write!(f, "note {} is tagged with missing tag {}", note, tag),
+            Problem::MissingTagParent { tag, parent } => write!(f, "tag {} has missing parent {}", tag, parent),
+            Problem::LinkMissingNote { link, note } => write!(f, "link {} is attached to missing note {}", link, note),
+            Problem::TagCycle(tags) => write!(f, "tags form a cycle: {}", tags.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" > ")),
+        }
+    }
+}
+
+// Only hashes that are 32 byte blobs can be read with hash_from_row.
+const WELL_FORMED_HASH: &str = "typeof(hash) = 'blob' AND length(hash) = 32";
+
+pub fn check_repository(db: &Connection) -> Result<Vec<Problem>, CommonplaceError> {
+    let mut problems = vec![];
+
+    for table in &["Blobs", "Notes", "NoteHistory"] {
+        let mut malformed_query = db.prepare(&format!("SELECT CAST(hash AS BLOB) FROM {} WHERE NOT ({})", table, WELL_FORMED_HASH))?;
+        problems.extend(malformed_query.query_map(params![], |row| {
+            Ok(Problem::MalformedHash { table, hash: row.get(0)? })
+        })?.collect::<Result<Vec<Problem>, rusqlite::Error>>()?);
+    }
+
+    let mut blob_query = db.prepare(&format!("SELECT hash, contents FROM Blobs WHERE {}", WELL_FORMED_HASH))?;
+    let mut rows = blob_query.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let hash = hash_from_row(row, "hash")?;
+        let contents: Vec<u8> = row.get("contents")?;
+        let actual = *blake3::hash(&contents).as_bytes();
+        if hash != actual {
+            problems.push(Problem::HashMismatch { hash, actual });
+        }
+    }
+
+    let mut note_query = db.prepare(&format!("SELECT id, hash FROM Notes WHERE {} AND hash NOT IN (SELECT hash FROM Blobs)", WELL_FORMED_HASH))?;
+    problems.extend(note_query.query_map(params![], |row| {
+        Ok(Problem::MissingNoteBlob { note: row.get("id")?, hash: hash_from_row(row, "hash")? })
+    })?.collect::<Result<Vec<Problem>, rusqlite::Error>>()?);
+
+    let mut history_query = db.prepare(&format!(
+        "SELECT note_id, hash, time FROM NoteHistory WHERE {} AND hash NOT IN (SELECT hash FROM Blobs)", WELL_FORMED_HASH
+    ))?;
+    problems.extend(history_query.query_map(params![], |row| {
+        Ok(Problem::MissingHistoryBlob { note: row.get("note_id")?, hash: hash_from_row(row, "hash")?, time: row.get("time")? })
+    })?.collect::<Result<Vec<Problem>, rusqlite::Error>>()?);
+
+    let mut tagmap_note_query = db.prepare("SELECT note_id, tag_id FROM TagMap WHERE note_id NOT IN (SELECT id FROM Notes)")?;
+    problems.extend(tagmap_note_query.query_map(params![], |row| {
+        Ok(Problem::TagMapMissingNote { note: row.get("note_id")?, tag: row.get("tag_id")? })
+    })?.collect::<Result<Vec<Problem>, rusqlite::Error>>()?);
+
+    let mut tagmap_tag_query = db.prepare("SELECT note_id, tag_id FROM TagMap WHERE tag_id NOT IN (SELECT id FROM Tags)")?;
+    problems.extend(tagmap_tag_query.query_map(params![], |row| {
+        Ok(Problem::TagMapMissingTag { note: row.get("note_id")?, tag: row.get("tag_id")? })
+    })?.collect::<Result<Vec<Problem>, rusqlite::Error>>()?);
+
+    let mut link_query = db.prepare(
+        "SELECT id, from_note AS note FROM Links WHERE from_note NOT IN (SELECT id FROM Notes)
+         UNION ALL SELECT id, to_note AS note FROM Links WHERE to_note NOT IN (SELECT id FROM Notes)"
+    )?;
+    problems.extend(link_query.query_map(params![], |row| {
+        Ok(Problem::LinkMissingNote { link: row.get("id")?, note: row.get("note")? })
+    })?.collect::<Result<Vec<Problem>, rusqlite::Error>>()?);
+
+    let mut tag_query = db.prepare("SELECT id, parent FROM Tags")?;
+    let parents = tag_query.query_map(params![], |row| {
+        Ok((row.get("id")?, row.get("parent")?))
+    })?.collect::<Result<HashMap<Uuid, Option<Uuid>>, rusqlite::Error>>()?;
+
+    let mut done: HashSet<Uuid> = HashSet::new();
+    let mut tags: Vec<&Uuid> = parents.keys().collect();
+    tags.sort();
+    for tag in tags {
+        let mut path: Vec<Uuid> = vec![];
+        let mut current = Some(*tag);
+        while let Some(id) = current {
+            if done.contains(&id) {
+                break;
+            }
+            if let Some(i) = path.iter().position(|x| *x == id) {
+                problems.push(Problem::TagCycle(path[i..].to_vec()));
+                break;
+            }
+            path.push(id);
+            current = match parents.get(&id) {
+                Some(parent) => *parent,
+                None => {
+                    let child = path[path.len() - 2];
+                    problems.push(Problem::MissingTagParent { tag: child, parent: id });
+                    None
+                },
+            };
+        }
+        done.extend(path);
+    }
+
+    Ok(problems)
+}
+
+// Fixes the problems that can be fixed without losing information, returning how many were fixed.
+// What was removed is logged as a single operation.
+pub fn repair_repository(db: &Connection, problems: &[Problem]) -> Result<usize, CommonplaceError> {
+    let tx = db.unchecked_transaction()?;
+    let mut changes = vec![];
+    for problem in problems {
+        match problem {
+            Problem::TagMapMissingNote { note, tag } | Problem::TagMapMissingTag { note, tag } => {
+                let deleted = tx.execute("DELETE FROM TagMap WHERE note_id = ?1 AND tag_id = ?2", params![note, tag])?;
+                if deleted > 0 {
+                    changes.push(Operation::UntagNote { note: NoteId { uuid: *note }, tag: TagId { uuid: *tag } });
+                }
+            },
+            Problem::LinkMissingNote { link, .. } => {
+                let deleted = tx.query_row("SELECT * FROM Links WHERE id = ?1", params![link], link_from_row).optional()?;
+                if let Some(link) = deleted {
+                    tx.execute("DELETE FROM Links WHERE id = ?1", params![link.id.uuid])?;
+                    changes.push(Operation::DeleteLink { link });
+                }
+            },
+            _ => {},
+        }
+    }
+    let repaired = changes.len();
+    if repaired > 0 {
+        log_operation(&tx, &tick(&tx)?, &Operation::RepairRepository { changes })?;
+    }
+    tx.commit()?;
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_memex;
+
+    #[test]
+    fn malformed_hashes_are_reported() {
+        let db = Connection::open_in_memory().unwrap();
+        init_memex(&db).unwrap();
+        db.execute("INSERT INTO Blobs (hash, contents) VALUES (?1, ?2)", params![vec![0xab_u8, 0xcd], b"contents".to_vec()]).unwrap();
+
+        let problems = check_repository(&db).unwrap();
+        assert_eq!(problems, vec![Problem::MalformedHash { table: "Blobs", hash: vec![0xab, 0xcd] }]);
+        assert!(!problems[0].is_repairable());
+    }
+}
@@ -1,13 +1,38 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::convert::TryInto;
-use rusqlite::params;
+use rusqlite::{params, OptionalExtension};
 use uuid::Uuid;
-pub use libcommonplace_types::{TagId, NoteId, TagRow, TagTree, Note};
+pub use libcommonplace_types::{TagId, NoteId, LinkId, TagRow, Link, LinkTarget, NewLink, ANNOTATION_LINK, Anchor, AnchorError, Rect, ResolvedAnchor, CardId, CardText, Card, ChangedCard, Review, Grade, Schedule, extract_cards, card_similarity, merge_lines, TagForest, TagNode, ROOT_TAG, Note, NoteHistoryEntry, Conflict, Resolution, Inventory, Timestamp, Operation, LogEntry, SearchHit, Query, QueryError, parse_query, DiffHunk, DiffLine, diff_lines};
 
 pub use rusqlite::Connection;
 
+mod repo;
+pub use repo::{REPO_FILENAME, read_repo_config, find_repo, new_repo_path};
+
+mod migrations;
+pub use migrations::{Migration, latest_schema_version, get_schema_version, pending_migrations, migrate_db};
+
+mod fsck;
+pub use fsck::{Problem, check_repository, repair_repository};
+
+mod cards;
+pub use cards::{sync_cards, sync_all_cards, get_card, get_cards_for_note, get_due_cards, review_card, get_orphaned_cards, get_changed_cards, delete_card};
+
+mod sync;
+pub use sync::{ImportReport, Peer, export_bundle, export_bundle_for, get_inventory, import_bundle, get_peers, forget_peer};
+
+mod conflicts;
+pub use conflicts::{list_conflicts, get_conflict, resolve_conflict};
+
+mod clock;
+pub use clock::{get_device_id, tick};
+
+mod oplog;
+pub use oplog::get_operation_log;
+use oplog::log_operation;
+
 // This file has all of the stuff that touches sqlite, look at libcommonplace_types for more
 // general functions.
 
@@ -15,6 +40,24 @@ pub use rusqlite::Connection;
 pub enum CommonplaceError {
     Sqlite(rusqlite::Error),
     Io(std::io::Error),
+    Query(QueryError),
+    // The repository was written by a newer version of commonplace.
+    UnsupportedSchemaVersion(u32),
+    // The bundle was exported from a repository with a different schema version.
+    UnsupportedBundleVersion(u32),
+    // This many of the blobs that the bundle needs are missing from it, or don't match their hash.
+    // That happens when the repository has lost blobs that the peer thinks it has, which forgetting
+    // this repository on the peer fixes.
+    IncompleteBundle(usize),
+    // A conflict was resolved by keeping a version that isn't part of it.
+    InvalidResolution,
+    RepositoryNotFound,
+    // A tag with that name already exists under the same parent.
+    TagNameTaken,
+    // The tag would become its own ancestor.
+    TagCycle,
+    // The anchor is for a different kind of document than the note it's in, or is on a URL.
+    InvalidAnchor,
 }
 
 impl From<std::io::Error> for CommonplaceError {
@@ -29,53 +72,116 @@ impl From<rusqlite::Error> for CommonplaceError {
     }
 }
 
-fn get_tag_tree_internal(tag_rows: &Vec<TagRow>, tagmap_rows: &Vec<(TagId, NoteId)>, root_id: Option<TagId>) -> Vec<TagTree> {
-    let mut children = vec![];
-
-    for tag_row in tag_rows {
-        if tag_row.parent == root_id {
-            children.push(TagTree {
-                id: tag_row.id,
-                name: tag_row.name.clone(),
-                children: get_tag_tree_internal(tag_rows, tagmap_rows, Some(tag_row.id)),
-                notes: tagmap_rows.iter().filter_map(|x| {
-                    if x.0 == tag_row.id {
-                        Some(x.1.clone())
-                    } else {
-                        None
-                    }
-                }).collect(),
-            });
-        }
+impl From<QueryError> for CommonplaceError {
+    fn from(err: QueryError) -> CommonplaceError {
+        CommonplaceError::Query(err)
     }
-
-    children
 }
 
-pub fn get_tag_tree(db: &Connection) -> Result<Vec<TagTree>, CommonplaceError> {
+// With include_hidden unset, hidden tags (and their descendants) are left out of the tree, along
+// with every note tagged with them.
+pub fn get_tag_tree(db: &Connection, include_hidden: bool) -> Result<TagForest, CommonplaceError> {
     let mut tag_query = db.prepare("SELECT id, name, parent FROM Tags")?;
-    let tag_rows = tag_query.query_map(params![], |row| {
+    let tag_rows: Vec<TagRow> = tag_query.query_map(params![], |row| {
         Ok(TagRow {
             id: TagId { uuid: row.get("id")? },
             name: row.get("name")?,
-            parent: {
-                let parent_uuid = row.get("parent")?;
-                match parent_uuid {
-                    Some(uuid) => Some(TagId { uuid }),
-                    None => None,
-                }
-            }
+            parent: row.get::<&str, Option<Uuid>>("parent")?.map(|uuid| TagId { uuid }),
         })
-    })?.map(|x| x.unwrap()).collect();
+    })?.collect::<Result<_, rusqlite::Error>>()?;
 
     let mut tagmap_query = db.prepare("SELECT tag_id, note_id FROM TagMap")?;
     let tagmap_rows: Vec<(TagId, NoteId)> = tagmap_query.query_map(params![], |row| {
         Ok((TagId { uuid: row.get("tag_id")? }, NoteId { uuid: row.get("note_id")?}))
+    })?.collect::<Result<_, rusqlite::Error>>()?;
+
+    let forest = TagForest::new(tag_rows.clone(), tagmap_rows.clone());
+    if include_hidden {
+        return Ok(forest);
+    }
+
+    let hidden_tags: HashSet<TagId> = get_hidden_tags(db)?.iter().flat_map(|tag| forest.descendants(tag)).collect();
+    let hidden_notes: HashSet<NoteId> = tagmap_rows.iter().filter(|x| hidden_tags.contains(&x.0)).map(|x| x.1).collect();
+
+    let tag_rows = tag_rows.into_iter().filter(|x| !hidden_tags.contains(&x.id)).collect();
+    let tagmap_rows = tagmap_rows.into_iter().filter(|x| !hidden_notes.contains(&x.1)).collect();
+    Ok(TagForest::new(tag_rows, tagmap_rows))
+}
+
+pub fn get_hidden_tags(db: &Connection) -> Result<Vec<TagId>, CommonplaceError> {
+    let mut query = db.prepare("SELECT tag_id FROM HiddenTags")?;
+    let res = query.query_map(params![], |row| {
+        Ok(TagId { uuid: row.get("tag_id")? })
     })?.map(|x| x.unwrap()).collect();
+    Ok(res)
+}
 
-    Ok(get_tag_tree_internal(&tag_rows, &tagmap_rows, None))
+fn set_tag_hidden_internal(db: &Connection, tag_id: Uuid, hidden: bool) -> Result<(), CommonplaceError> {
+    let changed = if hidden {
+        db.execute("INSERT OR IGNORE INTO HiddenTags (tag_id) VALUES (?1)", params![tag_id])?
+    } else {
+        db.execute("DELETE FROM HiddenTags WHERE tag_id = ?1", params![tag_id])?
+    };
+    if changed > 0 {
+        log_operation(db, &tick(db)?, &Operation::SetTagHidden { tag: TagId { uuid: tag_id }, hidden })?;
+    }
+    Ok(())
 }
 
+pub fn set_tag_hidden(db: &Connection, tag_id: Uuid, hidden: bool) -> Result<(), CommonplaceError> {
+    let tx = db.unchecked_transaction()?;
+    set_tag_hidden_internal(&tx, tag_id, hidden)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub const ARCHIVE_TAG: &str = "archive";
+
+// Tags the note with the root "archive" tag, creating it and making it hidden if needed.
+pub fn archive_note(db: &Connection, note: Uuid) -> Result<(), CommonplaceError> {
+    get_note(db, note)?;
+    let tx = db.unchecked_transaction()?;
+    create_tag_internal(&tx, vec![ARCHIVE_TAG.to_string()])?;
+    let tag_id = get_tag_id_by_name(&tx, vec![ARCHIVE_TAG.to_string()])?;
+    set_tag_hidden_internal(&tx, tag_id, true)?;
+    let now = tick(&tx)?;
+    let tagged = tx.execute(
+        "INSERT OR IGNORE INTO TagMap (note_id, tag_id, hlc, device) VALUES (?1, ?2, ?3, ?4)",
+        params![note, tag_id, now.hlc, now.device]
+    )?;
+    if tagged > 0 {
+        tx.execute("DELETE FROM TagMapTombstones WHERE note_id = ?1 AND tag_id = ?2", params![note, tag_id])?;
+        log_operation(&tx, &now, &Operation::TagNote { note: NoteId { uuid: note }, tag: TagId { uuid: tag_id } })?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Removes every hidden tag from the note, so that it shows up in the standard views again.
+pub fn unarchive_note(db: &Connection, note: Uuid) -> Result<(), CommonplaceError> {
+    get_note(db, note)?;
+    let tag_tree = get_tag_tree(db, true)?;
+    let hidden_tags = get_hidden_tags(db)?;
+    let tx = db.unchecked_transaction()?;
+    for tag in tag_tree.tags_for_note(&NoteId { uuid: note }) {
+        let path = tag_tree.tag_path(tag).unwrap_or_default();
+        if path.iter().any(|x| hidden_tags.contains(x)) {
+            untag_note_internal(&tx, note, tag.uuid)?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Hashes are stored as blobs, so a corrupt repository can have ones that aren't 32 bytes long,
+// which are a conversion error rather than a panic.
+pub(crate) fn hash_from_bytes(hash: &[u8]) -> Result<[u8; 32], rusqlite::Error> {
+    hash.try_into().map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Blob, Box::new(e)))
+}
+
+pub(crate) fn hash_from_row(row: &rusqlite::Row, column: &str) -> Result<[u8; 32], rusqlite::Error> {
+    hash_from_bytes(&row.get::<&str, Vec<u8>>(column)?)
+}
 
 pub fn get_all_notes(db: &Connection) -> Result<HashMap<Uuid, Note>, CommonplaceError> {
     let mut notes_query = db.prepare("SELECT * FROM Notes")?;
@@ -99,6 +205,20 @@ pub fn get_all_notes(db: &Connection) -> Result<HashMap<Uuid, Note>, Commonplace
     Ok(res)
 }
 
+pub fn get_note(db: &Connection, note_id: Uuid) -> Result<Note, CommonplaceError> {
+    Ok(db.query_row("SELECT * FROM Notes WHERE id = ?1", params![note_id], |row| {
+        let mut hash: [u8; 32] = [0; 32];
+        hash.copy_from_slice(&row.get::<&str, Vec<u8>>("hash")?[..]);
+        Ok(Note {
+            id: row.get("id")?,
+            hash,
+            name: row.get("name")?,
+            mimetype: row.get("mimetype")?,
+        })
+    })?)
+}
+
+// Archived notes never show up here, since they're tagged with a hidden tag.
 pub fn get_untagged_notes(db: &Connection) -> Result<Vec<Uuid>, CommonplaceError> {
     let mut query = db.prepare("SELECT Notes.id FROM Notes LEFT JOIN TagMap ON Notes.id = TagMap.note_id WHERE TagMap.tag_id is NULL")?;
     let res = query.query_map(params![], |row| {
@@ -107,8 +227,94 @@ pub fn get_untagged_notes(db: &Connection) -> Result<Vec<Uuid>, CommonplaceError
     Ok(res)
 }
 
+// Like get_tag_tree, notes tagged with a hidden tag (such as archived notes) are left out unless
+// include_hidden is set.
+pub fn query_notes(db: &Connection, query: &Query, include_hidden: bool) -> Result<Vec<NoteId>, CommonplaceError> {
+    let tag_tree = get_tag_tree(db, true)?;
+    let hidden_tags: HashSet<TagId> = if include_hidden {
+        HashSet::new()
+    } else {
+        get_hidden_tags(db)?.iter().flat_map(|tag| tag_tree.descendants(tag)).collect()
+    };
+
+    let notes = get_all_notes(db)?;
+    let mut matching: Vec<&Note> = notes.values().filter(|note| {
+        let tag_ids = tag_tree.tags_for_note(&NoteId { uuid: note.id });
+        if tag_ids.iter().any(|tag| hidden_tags.contains(tag)) {
+            return false;
+        }
+        let tags: Vec<Vec<String>> = tag_ids.iter().filter_map(|tag| {
+            tag_tree.tag_name(tag).cloned()
+        }).collect();
+        query.matches(note, &tags)
+    }).collect();
+    matching.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(matching.iter().map(|note| NoteId { uuid: note.id }).collect())
+}
+
+fn index_note_contents(db: &Connection, note: Uuid) -> Result<(), CommonplaceError> {
+    db.execute("DELETE FROM NotesFts WHERE note_id = ?1", params![note])?;
+    let (name, mimetype, contents): (String, String, Vec<u8>) = db.query_row(
+        "SELECT name, mimetype, contents FROM Notes LEFT JOIN Blobs ON Blobs.hash = Notes.hash WHERE Notes.id = ?1",
+        params![note],
+        |row| Ok((row.get("name")?, row.get("mimetype")?, row.get("contents")?))
+    )?;
+    let contents = if mimetype.starts_with("text/") {
+        String::from_utf8_lossy(&contents).to_string()
+    } else {
+        String::new()
+    };
+    db.execute(
+        "INSERT INTO NotesFts (note_id, name, contents) VALUES (?1, ?2, ?3)",
+        params![note, name, contents]
+    )?;
+    Ok(())
+}
+
+pub fn rebuild_search_index(db: &Connection) -> Result<(), CommonplaceError> {
+    db.execute("DELETE FROM NotesFts", params![])?;
+    for note in get_all_notes(db)?.keys() {
+        index_note_contents(db, *note)?;
+    }
+    Ok(())
+}
+
+// Adding or updating a note fails without the search index, so a repository that's missing it, no
+// matter what its schema version says, gets it back when it's opened. It can always be rebuilt from
+// the notes.
+fn ensure_search_index(db: &Connection) -> Result<(), CommonplaceError> {
+    let exists: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'NotesFts'",
+        params![],
+        |row| row.get(0)
+    )?;
+    if !exists {
+        let tx = db.unchecked_transaction()?;
+        tx.execute_batch(include_str!("migrations/002_notes_fts.sql"))?;
+        rebuild_search_index(&tx)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub fn search_notes(db: &Connection, query: &str) -> Result<Vec<SearchHit>, CommonplaceError> {
+    let mut search_query = db.prepare(
+        "SELECT note_id, name, snippet(NotesFts, 2, '[', ']', '...', 16) AS snippet, rank FROM NotesFts WHERE NotesFts MATCH ?1 ORDER BY rank"
+    )?;
+    let hits = search_query.query_map(params![query], |row| {
+        Ok(SearchHit {
+            note_id: NoteId { uuid: row.get("note_id")? },
+            name: row.get("name")?,
+            snippet: row.get("snippet")?,
+            rank: row.get("rank")?,
+        })
+    })?.collect::<Result<Vec<SearchHit>, rusqlite::Error>>()?;
+    Ok(hits)
+}
+
 pub fn init_memex(db: &Connection) -> Result<(), CommonplaceError> {
-    db.execute_batch(include_str!("setup.sql"))?;
+    migrate_db(db)?;
 
     Ok(())
 }
@@ -132,6 +338,53 @@ pub fn add_bytes_to_blobstore(db: &Connection, contents: Vec<u8>) -> Result<blak
     Ok(hash)
 }
 
+// Every table that refers to blobs needs to be listed here, otherwise gc will delete blobs that are
+// still in use.
+const UNREFERENCED_BLOBS: &str = "hash NOT IN (SELECT hash FROM Notes) AND hash NOT IN (SELECT hash FROM NoteHistory)
+    AND hash NOT IN (SELECT base FROM Conflicts) AND hash NOT IN (SELECT ours FROM Conflicts) AND hash NOT IN (SELECT theirs FROM Conflicts)";
+
+#[derive(Debug)]
+pub struct GcReport {
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+// Deletes blobs that aren't referred to by any note, note history or conflict, returning how much
+// was (or with dry_run, would be) reclaimed. Space isn't actually returned to the filesystem until
+// vacuum is run.
+pub fn gc(db: &Connection, dry_run: bool) -> Result<GcReport, CommonplaceError> {
+    let tx = db.unchecked_transaction()?;
+    let (blobs, bytes): (i64, i64) = tx.query_row(
+        &format!("SELECT COUNT(*), COALESCE(SUM(length(contents)), 0) FROM Blobs WHERE {}", UNREFERENCED_BLOBS),
+        params![],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    )?;
+    if !dry_run && blobs > 0 {
+        let hashes = {
+            let mut query = tx.prepare(&format!("SELECT hash FROM Blobs WHERE {}", UNREFERENCED_BLOBS))?;
+            let hashes = query.query_map(params![], |row| {
+                let mut hash: [u8; 32] = [0; 32];
+                hash.copy_from_slice(&row.get::<usize, Vec<u8>>(0)?[..]);
+                Ok(hash)
+            })?.collect::<Result<Vec<[u8; 32]>, rusqlite::Error>>()?;
+            hashes
+        };
+        tx.execute(&format!("DELETE FROM Blobs WHERE {}", UNREFERENCED_BLOBS), params![])?;
+        log_operation(&tx, &tick(&tx)?, &Operation::CollectGarbage { blobs: hashes })?;
+    }
+    tx.commit()?;
+
+    Ok(GcReport {
+        blobs: blobs.try_into().unwrap(),
+        bytes: bytes.try_into().unwrap(),
+    })
+}
+
+pub fn vacuum(db: &Connection) -> Result<(), CommonplaceError> {
+    db.execute_batch("VACUUM")?;
+    Ok(())
+}
+
 pub fn blobstore_get(db: &Connection, hash: blake3::Hash) -> Result<Vec<u8>, CommonplaceError> {
     Ok(db.query_row(
         "SELECT contents FROM Blobs WHERE hash = ?1",
@@ -140,6 +393,24 @@ pub fn blobstore_get(db: &Connection, hash: blake3::Hash) -> Result<Vec<u8>, Com
     )?)
 }
 
+pub fn diff_blobs(db: &Connection, old: blake3::Hash, new: blake3::Hash) -> Result<Vec<DiffHunk>, CommonplaceError> {
+    let old = blobstore_get(db, old)?;
+    let new = blobstore_get(db, new)?;
+    Ok(diff_lines(&String::from_utf8_lossy(&old), &String::from_utf8_lossy(&new), 3))
+}
+
+pub fn diff_note_version(db: &Connection, note: Uuid, version: &NoteHistoryEntry) -> Result<Vec<DiffHunk>, CommonplaceError> {
+    let current = get_note(db, note)?;
+    diff_blobs(db, version.hash.into(), current.hash.into())
+}
+
+// The newest past version with different contents from the current one, skipping versions that
+// were only renamed, or None if the contents have never changed.
+pub fn get_previous_contents(db: &Connection, note: Uuid) -> Result<Option<NoteHistoryEntry>, CommonplaceError> {
+    let current = get_note(db, note)?;
+    Ok(get_note_history(db, note)?.into_iter().rev().find(|x| x.hash != current.hash))
+}
+
 pub fn get_note_contents(db: &Connection, note_id: Uuid) -> Result<Vec<u8>, CommonplaceError> {
     Ok(db.query_row(
         "SELECT contents FROM Blobs LEFT JOIN Notes ON Blobs.hash = Notes.hash WHERE Notes.id = ?1",
@@ -156,15 +427,28 @@ pub fn get_note_size(db: &Connection, note_id: Uuid) -> Result<u64, CommonplaceE
     )?.try_into().unwrap())
 }
 
-pub fn open_db() -> Result<Connection, CommonplaceError> {
-    Ok(Connection::open("index.db")?)
+// Opens the repository without upgrading its schema, for tools that need to inspect pending
+// migrations. Most things should use open_db instead.
+pub fn open_db_unmigrated(repo: &Path) -> Result<Connection, CommonplaceError> {
+    Ok(Connection::open(repo)?)
+}
+
+// Empty databases are left alone here, they only get a schema once init_memex is called.
+pub fn open_db(repo: &Path) -> Result<Connection, CommonplaceError> {
+    let db = open_db_unmigrated(repo)?;
+    if get_schema_version(&db)? > 0 {
+        migrate_db(&db)?;
+        ensure_search_index(&db)?;
+    }
+    Ok(db)
 }
 
 pub fn add_note(db: &Connection, name: String, filename: PathBuf) -> Result<Uuid, CommonplaceError> {
     // TODO: check that file doesn't exist
 
     let id = Uuid::new_v4();
-    let hash = add_file_to_blobstore(db, filename.clone())?.as_bytes().to_vec();
+    let tx = db.unchecked_transaction()?;
+    let hash = add_file_to_blobstore(&tx, filename.clone())?.as_bytes().to_vec();
     let mimetype = match filename.to_string_lossy().split(".").last() {
         Some("md") => "text/markdown",
         Some("txt") => "text/plain",
@@ -173,18 +457,56 @@ pub fn add_note(db: &Connection, name: String, filename: PathBuf) -> Result<Uuid
         Some("jpeg") => "image/jpeg",
         Some("jpg") => "image/jpeg",
         Some("gif") => "image/gif",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
         _ => "application/octet-stream",
     };
 
-    db.execute(
-        "INSERT INTO Notes (id, hash, name, mimetype) VALUES (?1, ?2, ?3, ?4)",
-        params![id, hash, name, mimetype]
+    let now = tick(&tx)?;
+    tx.execute(
+        "INSERT INTO Notes (id, hash, name, mimetype, hlc, device) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![id, hash, name, mimetype, now.hlc, now.device]
     )?;
+    log_operation(&tx, &now, &Operation::AddNote { note: get_note(&tx, id)? })?;
+    index_note_contents(&tx, id)?;
+    sync_cards(&tx, id)?;
+    tx.commit()?;
 
     Ok(id)
 }
 
-pub fn create_tag(db: &Connection, tag: Vec<String>) -> Result<(), CommonplaceError> {
+pub fn delete_note(db: &Connection, note: Uuid) -> Result<(), CommonplaceError> {
+    let deleted = get_note(db, note)?;
+
+    let tx = db.unchecked_transaction()?;
+    delete_note_internal(&tx, note)?;
+    let now = tick(&tx)?;
+    tx.execute(
+        "INSERT OR REPLACE INTO Tombstones (id, time, hlc, device) VALUES (?1, strftime('%s', 'now'), ?2, ?3)",
+        params![note, now.hlc, now.device]
+    )?;
+    log_operation(&tx, &now, &Operation::DeleteNote { note: deleted })?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// Deletes the note and everything that refers to it, without leaving a tombstone, and without a
+// transaction of its own.
+fn delete_note_internal(db: &Connection, note: Uuid) -> Result<(), CommonplaceError> {
+    db.execute("DELETE FROM TagMap WHERE note_id = ?1", params![note])?;
+    db.execute("DELETE FROM NoteHistory WHERE note_id = ?1", params![note])?;
+    db.execute("DELETE FROM Links WHERE from_note = ?1 OR to_note = ?1", params![note])?;
+    db.execute("DELETE FROM ReviewLog WHERE card_id IN (SELECT id FROM Cards WHERE note_id = ?1)", params![note])?;
+    db.execute("DELETE FROM Cards WHERE note_id = ?1", params![note])?;
+    db.execute("DELETE FROM NotesFts WHERE note_id = ?1", params![note])?;
+    db.execute("DELETE FROM Conflicts WHERE note_id = ?1", params![note])?;
+    db.execute("DELETE FROM Notes WHERE id = ?1", params![note])?;
+    Ok(())
+}
+
+fn create_tag_internal(db: &Connection, tag: Vec<String>) -> Result<(), CommonplaceError> {
     let mut parent: Option<Uuid> = None;
 
     for tag_part in tag {
@@ -195,12 +517,14 @@ pub fn create_tag(db: &Connection, tag: Vec<String>) -> Result<(), CommonplaceEr
         ) {
             Ok(id) => parent = id,
             Err(_) => {
-                db.execute("INSERT INTO Tags (id, name, parent) VALUES (?1, ?2, ?3)", params![Uuid::new_v4(), tag_part, parent])?;
-                parent = db.query_row(
-                    "SELECT * FROM Tags WHERE name = ?1 AND parent IS ?2",
-                    params![tag_part, parent],
-                    |row| row.get("id")
+                let now = tick(db)?;
+                let id = Uuid::new_v4();
+                db.execute(
+                    "INSERT INTO Tags (id, name, parent, hlc, device) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![id, tag_part, parent, now.hlc, now.device]
                 )?;
+                log_operation(db, &now, &Operation::CreateTag { tag: get_tag_row(db, id)? })?;
+                parent = Some(id);
             },
         }
     }
@@ -208,58 +532,422 @@ pub fn create_tag(db: &Connection, tag: Vec<String>) -> Result<(), CommonplaceEr
     Ok(())
 }
 
-pub fn delete_tag_by_uuid(db: &Connection, tag_id: Uuid) -> Result<(), CommonplaceError> {
+pub fn create_tag(db: &Connection, tag: Vec<String>) -> Result<(), CommonplaceError> {
+    let tx = db.unchecked_transaction()?;
+    create_tag_internal(&tx, tag)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn get_tag_row(db: &Connection, tag_id: Uuid) -> Result<TagRow, CommonplaceError> {
+    Ok(db.query_row("SELECT id, name, parent FROM Tags WHERE id = ?1", params![tag_id], |row| {
+        Ok(TagRow {
+            id: TagId { uuid: row.get("id")? },
+            name: row.get("name")?,
+            parent: row.get::<&str, Option<Uuid>>("parent")?.map(|uuid| TagId { uuid }),
+        })
+    })?)
+}
+
+// The UNIQUE (name, parent) constraint on Tags doesn't apply to root tags, since NULLs are never
+// equal to each other, so this is checked by hand.
+fn check_tag_name_free(db: &Connection, tag_id: Uuid, name: &str, parent: Option<Uuid>) -> Result<(), CommonplaceError> {
+    let taken: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM Tags WHERE name = ?1 AND parent IS ?2 AND id != ?3",
+        params![name, parent, tag_id],
+        |row| row.get(0)
+    )?;
+    if taken {
+        Err(CommonplaceError::TagNameTaken)
+    } else {
+        Ok(())
+    }
+}
+
+// Whether the tag is the ancestor, or has it as an ancestor.
+fn has_ancestor(db: &Connection, tag: Option<Uuid>, ancestor: Uuid) -> Result<bool, CommonplaceError> {
+    let mut tag = tag;
+    while let Some(id) = tag {
+        if id == ancestor {
+            return Ok(true);
+        }
+        tag = db.query_row("SELECT parent FROM Tags WHERE id = ?1", params![id], |row| row.get("parent"))?;
+    }
+    Ok(false)
+}
+
+pub fn rename_tag(db: &Connection, tag_id: Uuid, name: String) -> Result<(), CommonplaceError> {
+    let tag = get_tag_row(db, tag_id)?;
+    check_tag_name_free(db, tag_id, &name, tag.parent.map(|x| x.uuid))?;
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    tx.execute("UPDATE Tags SET name = ?1, hlc = ?2, device = ?3 WHERE id = ?4", params![name, now.hlc, now.device, tag_id])?;
+    log_operation(&tx, &now, &Operation::RenameTag { tag: tag.id, from: tag.name, to: name })?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Moves a tag (along with all of its children and notes) under a new parent, or to the root if
+// parent is None.
+pub fn move_tag(db: &Connection, tag_id: Uuid, parent: Option<Uuid>) -> Result<(), CommonplaceError> {
+    let tag = get_tag_row(db, tag_id)?;
+    if has_ancestor(db, parent, tag_id)? {
+        return Err(CommonplaceError::TagCycle);
+    }
+
+    check_tag_name_free(db, tag_id, &tag.name, parent)?;
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    tx.execute("UPDATE Tags SET parent = ?1, hlc = ?2, device = ?3 WHERE id = ?4", params![parent, now.hlc, now.device, tag_id])?;
+    log_operation(&tx, &now, &Operation::MoveTag { tag: tag.id, from: tag.parent, to: parent.map(|uuid| TagId { uuid }) })?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn merge_tags_internal(db: &Connection, src: Uuid, dst: Uuid, now: &Timestamp) -> Result<(), CommonplaceError> {
+    db.execute(
+        "DELETE FROM TagMapTombstones WHERE tag_id = ?2 AND note_id IN (SELECT note_id FROM TagMap WHERE tag_id = ?1)",
+        params![src, dst]
+    )?;
+    db.execute(
+        "INSERT OR IGNORE INTO TagMap (note_id, tag_id, hlc, device) SELECT note_id, ?2, ?3, ?4 FROM TagMap WHERE tag_id = ?1",
+        params![src, dst, now.hlc, now.device]
+    )?;
+    db.execute("DELETE FROM TagMap WHERE tag_id = ?1", params![src])?;
+
+    let mut children_query = db.prepare("SELECT id, name FROM Tags WHERE parent = ?1")?;
+    let children: Vec<(Uuid, String)> = children_query.query_map(params![src], |row| {
+        Ok((row.get("id")?, row.get("name")?))
+    })?.collect::<Result<Vec<_>, rusqlite::Error>>()?;
+    for (child, name) in children {
+        let existing: Option<Uuid> = match db.query_row(
+            "SELECT id FROM Tags WHERE name = ?1 AND parent = ?2",
+            params![name, dst],
+            |row| row.get("id")
+        ) {
+            Ok(id) => Some(id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+        match existing {
+            Some(existing) => merge_tags_internal(db, child, existing, now)?,
+            None => {
+                db.execute("UPDATE Tags SET parent = ?1, hlc = ?2, device = ?3 WHERE id = ?4", params![dst, now.hlc, now.device, child])?;
+            },
+        }
+    }
+
+    // src's notes and children have all been moved to dst, so there's nothing left to untag.
+    delete_tag_internal(db, src, now)?;
+    Ok(())
+}
+
+// Folds src into dst: every note tagged with src is tagged with dst instead, and src's children
+// become children of dst, merging recursively with any children of dst that have the same name.
+pub fn merge_tags(db: &Connection, src: Uuid, dst: Uuid) -> Result<(), CommonplaceError> {
+    if has_ancestor(db, Some(dst), src)? {
+        return Err(CommonplaceError::TagCycle);
+    }
+
+    let src_row = get_tag_row(db, src)?;
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    merge_tags_internal(&tx, src, dst, &now)?;
+    log_operation(&tx, &now, &Operation::MergeTags { src: src_row, dst: TagId { uuid: dst } })?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Finds every (note, tag) pair where the note is also tagged with a descendant of the tag, and
+// unless dry_run is set, untags them.
+pub fn remove_redundant_tags(db: &Connection, dry_run: bool) -> Result<Vec<(NoteId, TagId)>, CommonplaceError> {
+    let tag_tree = get_tag_tree(db, true)?;
+    let mut redundant = vec![];
+    for note in get_all_notes(db)?.keys() {
+        let note = NoteId { uuid: *note };
+        let tags = tag_tree.tags_for_note(&note);
+        let canonical = tag_tree.canonicalize_tags(tags);
+        for tag in tags {
+            if !canonical.contains(tag) {
+                redundant.push((note, *tag));
+            }
+        }
+    }
+
+    if !dry_run {
+        let tx = db.unchecked_transaction()?;
+        for (note, tag) in &redundant {
+            untag_note_internal(&tx, note.uuid, tag.uuid)?;
+        }
+        tx.commit()?;
+    }
+
+    Ok(redundant)
+}
+
+// Deletes the tag, leaving tombstones for it and for its descendants, which go along with it.
+// Notes tagged with any of them are untagged first, and the (note, tag) pairs that were untagged
+// are returned so that they can be logged along with the tag.
+fn delete_tag_internal(db: &Connection, tag_id: Uuid, now: &Timestamp) -> Result<Vec<(NoteId, TagId)>, CommonplaceError> {
+    let untagged = {
+        let mut query = db.prepare(
+            "WITH RECURSIVE Subtree(id) AS (SELECT ?1 UNION SELECT Tags.id FROM Tags JOIN Subtree ON Tags.parent = Subtree.id)
+             SELECT note_id, tag_id FROM TagMap WHERE tag_id IN Subtree"
+        )?;
+        let untagged = query.query_map(params![tag_id], |row| Ok((NoteId { uuid: row.get(0)? }, TagId { uuid: row.get(1)? })))?
+            .collect::<Result<Vec<(NoteId, TagId)>, rusqlite::Error>>()?;
+        untagged
+    };
+    for (note, tag) in &untagged {
+        db.execute("DELETE FROM TagMap WHERE note_id = ?1 AND tag_id = ?2", params![note.uuid, tag.uuid])?;
+    }
+    db.execute(
+        "WITH RECURSIVE Subtree(id) AS (SELECT ?1 UNION SELECT Tags.id FROM Tags JOIN Subtree ON Tags.parent = Subtree.id)
+         INSERT OR REPLACE INTO TagTombstones (id, hlc, device) SELECT id, ?2, ?3 FROM Subtree",
+        params![tag_id, now.hlc, now.device]
+    )?;
     db.execute("DELETE FROM Tags WHERE id = ?1", params![tag_id])?;
+    Ok(untagged)
+}
+
+pub fn delete_tag_by_uuid(db: &Connection, tag_id: Uuid) -> Result<(), CommonplaceError> {
+    let tag = get_tag_row(db, tag_id)?;
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    let untagged = delete_tag_internal(&tx, tag_id, &now)?;
+    log_operation(&tx, &now, &Operation::DeleteTag { tag, untagged })?;
+    tx.commit()?;
 
     Ok(())
 }
 
 pub fn delete_tag(db: &Connection, tag: Vec<String>) -> Result<(), CommonplaceError> {
     let id = get_tag_id_by_name(db, tag)?;
-    db.execute("DELETE FROM Tags WHERE id = ?1", params![id])?;
+    delete_tag_by_uuid(db, id)
+}
 
+pub fn tag_note_by_uuid(db: &Connection, note: Uuid, tag_id: Uuid) -> Result<(), CommonplaceError> {
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    tx.execute(
+        "INSERT INTO TagMap (note_id, tag_id, hlc, device) VALUES (?1, ?2, ?3, ?4)",
+        params![note, tag_id, now.hlc, now.device]
+    )?;
+    tx.execute("DELETE FROM TagMapTombstones WHERE note_id = ?1 AND tag_id = ?2", params![note, tag_id])?;
+    log_operation(&tx, &now, &Operation::TagNote { note: NoteId { uuid: note }, tag: TagId { uuid: tag_id } })?;
+    tx.commit()?;
     Ok(())
 }
 
-pub fn tag_note_by_uuid(db: &Connection, note: Uuid, tag_id: Uuid) -> Result<(), CommonplaceError> {
-    db.execute("INSERT INTO TagMap (note_id, tag_id) VALUES (?1, ?2)", params![note, tag_id])?;
+// Untagging leaves a tombstone, so that syncing can tell it apart from a note that was never
+// tagged.
+fn untag_note_internal(db: &Connection, note: Uuid, tag_id: Uuid) -> Result<(), CommonplaceError> {
+    if db.execute("DELETE FROM TagMap WHERE note_id = ?1 AND tag_id = ?2", params![note, tag_id])? > 0 {
+        let now = tick(db)?;
+        db.execute(
+            "INSERT OR REPLACE INTO TagMapTombstones (note_id, tag_id, hlc, device) VALUES (?1, ?2, ?3, ?4)",
+            params![note, tag_id, now.hlc, now.device]
+        )?;
+        log_operation(db, &now, &Operation::UntagNote { note: NoteId { uuid: note }, tag: TagId { uuid: tag_id } })?;
+    }
     Ok(())
 }
 
 pub fn untag_note_by_uuid(db: &Connection, note: Uuid, tag_id: Uuid) -> Result<(), CommonplaceError> {
-    db.execute("DELETE FROM TagMap WHERE note_id = ?1 AND tag_id = ?2", params![note, tag_id])?;
+    let tx = db.unchecked_transaction()?;
+    untag_note_internal(&tx, note, tag_id)?;
+    tx.commit()?;
     Ok(())
 }
 
 pub fn tag_note(db: &Connection, note: Uuid, tag: Vec<String>) -> Result<(), CommonplaceError> {
     let tag_id = get_tag_id_by_name(db, tag)?;
-    db.execute("INSERT INTO TagMap (note_id, tag_id) VALUES (?1, ?2)", params![note, tag_id])?;
-    Ok(())
+    tag_note_by_uuid(db, note, tag_id)
 }
 
 pub fn untag_note(db: &Connection, note: Uuid, tag: Vec<String>) -> Result<(), CommonplaceError> {
     let tag_id = get_tag_id_by_name(db, tag)?;
-    db.execute("DELETE FROM TagMap WHERE note_id = ?1 AND tag_id = ?2", params![note, tag_id])?;
-    Ok(())
+    untag_note_by_uuid(db, note, tag_id)
 }
 
 pub fn update_note(db: &Connection, note: Uuid, filename: PathBuf) -> Result<(), CommonplaceError> {
-    let hash = add_file_to_blobstore(db, filename)?.as_bytes().to_vec();
-    db.execute("UPDATE Notes SET hash = ?1 WHERE id = ?2", params![hash, note])?;
+    let old = get_note(db, note)?;
+    let tx = db.unchecked_transaction()?;
+    let hash = add_file_to_blobstore(&tx, filename)?;
+    let now = tick(&tx)?;
+    tx.execute("UPDATE Notes SET hash = ?1, hlc = ?2, device = ?3 WHERE id = ?4", params![hash.as_bytes().to_vec(), now.hlc, now.device, note])?;
+    log_operation(&tx, &now, &Operation::UpdateNote { note: NoteId { uuid: note }, from: old.hash, to: *hash.as_bytes() })?;
+    index_note_contents(&tx, note)?;
+    sync_cards(&tx, note)?;
+    tx.commit()?;
     Ok(())
 }
 
 pub fn update_note_bytes(db: &Connection, note: Uuid, contents: Vec<u8>) -> Result<(), CommonplaceError> {
-    let hash = add_bytes_to_blobstore(db, contents)?.as_bytes().to_vec();
-    db.execute("UPDATE Notes SET hash = ?1 WHERE id = ?2", params![hash, note])?;
+    let old = get_note(db, note)?;
+    let tx = db.unchecked_transaction()?;
+    let hash = add_bytes_to_blobstore(&tx, contents)?;
+    let now = tick(&tx)?;
+    tx.execute("UPDATE Notes SET hash = ?1, hlc = ?2, device = ?3 WHERE id = ?4", params![hash.as_bytes().to_vec(), now.hlc, now.device, note])?;
+    log_operation(&tx, &now, &Operation::UpdateNote { note: NoteId { uuid: note }, from: old.hash, to: *hash.as_bytes() })?;
+    index_note_contents(&tx, note)?;
+    sync_cards(&tx, note)?;
+    tx.commit()?;
     Ok(())
 }
 
 pub fn rename_note(db: &Connection, note: Uuid, name: String) -> Result<(), CommonplaceError> {
-    db.execute("UPDATE Notes SET name = ?1 WHERE id = ?2", params![name, note])?;
+    let old = get_note(db, note)?;
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    tx.execute("UPDATE Notes SET name = ?1, hlc = ?2, device = ?3 WHERE id = ?4", params![name, now.hlc, now.device, note])?;
+    log_operation(&tx, &now, &Operation::RenameNote { note: NoteId { uuid: note }, from: old.name, to: name })?;
+    index_note_contents(&tx, note)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn get_note_history(db: &Connection, note: Uuid) -> Result<Vec<NoteHistoryEntry>, CommonplaceError> {
+    let mut history_query = db.prepare("SELECT * FROM NoteHistory WHERE note_id = ?1 ORDER BY time, rowid")?;
+    let history = history_query.query_map(params![note], |row| {
+        Ok(NoteHistoryEntry {
+            note_id: NoteId { uuid: row.get("note_id")? },
+            hash: hash_from_row(row, "hash")?,
+            name: row.get("name")?,
+            mimetype: row.get("mimetype")?,
+            time: row.get("time")?,
+        })
+    })?.collect::<Result<Vec<_>, rusqlite::Error>>()?;
+    Ok(history)
+}
+
+pub fn get_note_version_contents(db: &Connection, version: &NoteHistoryEntry) -> Result<Vec<u8>, CommonplaceError> {
+    blobstore_get(db, version.hash.into())
+}
+
+// The current state of the note is pushed into NoteHistory by the note_update_history trigger, so
+// restoring is itself undoable.
+pub fn restore_note_version(db: &Connection, note: Uuid, version: &NoteHistoryEntry) -> Result<(), CommonplaceError> {
+    let old = get_note(db, note)?;
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    tx.execute(
+        "UPDATE Notes SET hash = ?1, name = ?2, mimetype = ?3, hlc = ?4, device = ?5 WHERE id = ?6",
+        params![version.hash.to_vec(), version.name, version.mimetype, now.hlc, now.device, note]
+    )?;
+    log_operation(&tx, &now, &Operation::RestoreNoteVersion { from: old, to: get_note(&tx, note)? })?;
+    index_note_contents(&tx, note)?;
+    sync_cards(&tx, note)?;
+    tx.commit()?;
     Ok(())
 }
 
+fn anchor_from_row(row: &rusqlite::Row, column: &str) -> Result<Option<Anchor>, rusqlite::Error> {
+    let anchor: Option<String> = row.get(column)?;
+    anchor.map(|x| x.parse().map_err(|e: AnchorError| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })).transpose()
+}
+
+fn link_from_row(row: &rusqlite::Row) -> Result<Link, rusqlite::Error> {
+    // The table has a CHECK that exactly one of these is set.
+    let to = match row.get::<&str, Option<Uuid>>("to_note")? {
+        Some(uuid) => LinkTarget::Note(NoteId { uuid }),
+        None => LinkTarget::Url(row.get("to_url")?),
+    };
+    Ok(Link {
+        id: LinkId { uuid: row.get("id")? },
+        from: NoteId { uuid: row.get("from_note")? },
+        from_anchor: anchor_from_row(row, "from_anchor")?,
+        to,
+        to_anchor: anchor_from_row(row, "to_anchor")?,
+        kind: row.get("kind")?,
+    })
+}
+
+pub fn resolve_anchor(db: &Connection, note: Uuid, anchor: &Anchor) -> Result<ResolvedAnchor, CommonplaceError> {
+    let note = get_note(db, note)?;
+    if !anchor.applies_to(&note.mimetype) {
+        return Err(CommonplaceError::InvalidAnchor);
+    }
+    let (anchor, line) = match *anchor {
+        Anchor::Text { start, end } => {
+            let contents = get_note_contents(db, note.id)?;
+            let start = std::cmp::min(start, contents.len() as u64);
+            let end = std::cmp::min(end, contents.len() as u64);
+            let line = contents[..start as usize].iter().filter(|x| **x == b'\n').count() as u64;
+            (Anchor::Text { start, end }, Some(line))
+        },
+        ref anchor => (anchor.clone(), None),
+    };
+    Ok(ResolvedAnchor {
+        note: NoteId { uuid: note.id },
+        mimetype: note.mimetype,
+        anchor,
+        line,
+    })
+}
+
+pub fn add_link(db: &Connection, from: Uuid, link: NewLink) -> Result<Uuid, CommonplaceError> {
+    get_note(db, from)?;
+    if let Some(anchor) = &link.from_anchor {
+        resolve_anchor(db, from, anchor)?;
+    }
+    let (to_note, to_url) = match link.to {
+        LinkTarget::Note(note) => {
+            get_note(db, note.uuid)?;
+            if let Some(anchor) = &link.to_anchor {
+                resolve_anchor(db, note.uuid, anchor)?;
+            }
+            (Some(note.uuid), None)
+        },
+        LinkTarget::Url(_) if link.to_anchor.is_some() => return Err(CommonplaceError::InvalidAnchor),
+        LinkTarget::Url(url) => (None, Some(url)),
+    };
+
+    let id = Uuid::new_v4();
+    let tx = db.unchecked_transaction()?;
+    let now = tick(&tx)?;
+    tx.execute(
+        "INSERT INTO Links (id, from_note, from_anchor, to_note, to_url, to_anchor, kind) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![id, from, link.from_anchor.map(|x| x.to_string()), to_note, to_url, link.to_anchor.map(|x| x.to_string()), link.kind]
+    )?;
+    let link = tx.query_row("SELECT * FROM Links WHERE id = ?1", params![id], link_from_row)?;
+    log_operation(&tx, &now, &Operation::AddLink { link })?;
+    tx.commit()?;
+    Ok(id)
+}
+
+pub fn delete_link(db: &Connection, link: Uuid) -> Result<(), CommonplaceError> {
+    let deleted = db.query_row("SELECT * FROM Links WHERE id = ?1", params![link], link_from_row).optional()?;
+    let tx = db.unchecked_transaction()?;
+    tx.execute("DELETE FROM Links WHERE id = ?1", params![link])?;
+    if let Some(link) = deleted {
+        let now = tick(&tx)?;
+        tx.execute(
+            "INSERT OR IGNORE INTO LinkTombstones (id, hlc, device) VALUES (?1, ?2, ?3)",
+            params![link.id.uuid, now.hlc, now.device]
+        )?;
+        log_operation(&tx, &now, &Operation::DeleteLink { link })?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Links from this note to other notes and URLs.
+pub fn get_links(db: &Connection, note: Uuid) -> Result<Vec<Link>, CommonplaceError> {
+    let mut query = db.prepare("SELECT * FROM Links WHERE from_note = ?1 ORDER BY rowid")?;
+    let links = query.query_map(params![note], link_from_row)?.collect::<Result<Vec<Link>, rusqlite::Error>>()?;
+    Ok(links)
+}
+
+// Links from other notes to this one, which is how annotations of a note are found.
+pub fn get_backlinks(db: &Connection, note: Uuid) -> Result<Vec<Link>, CommonplaceError> {
+    let mut query = db.prepare("SELECT * FROM Links WHERE to_note = ?1 ORDER BY rowid")?;
+    let links = query.query_map(params![note], link_from_row)?.collect::<Result<Vec<Link>, rusqlite::Error>>()?;
+    Ok(links)
+}
+
 pub fn get_tag_id_by_name(db: &Connection, tag: Vec<String>) -> Result<Uuid, CommonplaceError> {
     let mut id: Option<Uuid> = None;
 
@@ -273,3 +961,49 @@ pub fn get_tag_id_by_name(db: &Connection, tag: Vec<String>) -> Result<Uuid, Com
 
     Ok(id.unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged_notes(db: &Connection, tag: Vec<String>) -> HashSet<Uuid> {
+        let tag = get_tag_id_by_name(db, tag).unwrap();
+        let mut query = db.prepare("SELECT note_id FROM TagMap WHERE tag_id = ?1").unwrap();
+        let notes = query.query_map(params![tag], |row| row.get(0)).unwrap();
+        notes.collect::<Result<_, rusqlite::Error>>().unwrap()
+    }
+
+    #[test]
+    fn merging_tags_merges_children_with_the_same_name() {
+        let db = Connection::open_in_memory().unwrap();
+        init_memex(&db).unwrap();
+        let path = std::env::temp_dir().join(format!("commonplace-test-{}.md", Uuid::new_v4()));
+        std::fs::write(&path, "contents").unwrap();
+        let notes: Vec<Uuid> = (0..3).map(|i| add_note(&db, format!("{}.md", i), path.clone()).unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let tag = |name: &str| name.split('/').map(String::from).collect::<Vec<String>>();
+        for name in &["a/c", "b/c"] {
+            create_tag(&db, tag(name)).unwrap();
+        }
+        let id = |name: &str| get_tag_id_by_name(&db, tag(name)).unwrap();
+        let (a, a_c, b) = (id("a"), id("a/c"), id("b"));
+        tag_note_by_uuid(&db, notes[0], a).unwrap();
+        tag_note_by_uuid(&db, notes[1], a_c).unwrap();
+        tag_note_by_uuid(&db, notes[2], id("b/c")).unwrap();
+
+        merge_tags(&db, a, b).unwrap();
+        assert_eq!(tagged_notes(&db, tag("b")), [notes[0]].iter().copied().collect());
+        assert_eq!(tagged_notes(&db, tag("b/c")), [notes[1], notes[2]].iter().copied().collect());
+        assert!(matches!(get_tag_id_by_name(&db, tag("a")), Err(CommonplaceError::Sqlite(rusqlite::Error::QueryReturnedNoRows))));
+
+        let tags: i64 = db.query_row("SELECT COUNT(*) FROM Tags", params![], |row| row.get(0)).unwrap();
+        assert_eq!(tags, 2);
+        let mut tombstones = db.prepare("SELECT id FROM TagTombstones").unwrap();
+        let tombstones: HashSet<Uuid> = tombstones.query_map(params![], |row| row.get(0)).unwrap()
+            .collect::<Result<_, rusqlite::Error>>().unwrap();
+        assert_eq!(tombstones, [a, a_c].iter().copied().collect());
+        let untagged: i64 = db.query_row("SELECT COUNT(*) FROM TagMap WHERE tag_id IN (?1, ?2)", params![a, a_c], |row| row.get(0)).unwrap();
+        assert_eq!(untagged, 0);
+    }
+}
@@ -0,0 +1,153 @@
+use rusqlite::params;
+use crate::{Connection, CommonplaceError, rebuild_search_index, sync_all_cards};
+use crate::clock::init_clock;
+
+// The schema version is stored in `PRAGMA user_version`. Version 0 is an empty database, and
+// version 1 is the original setup.sql. Every schema change after that is a new migration appended
+// to this list — never edit one that has already shipped, since existing repositories won't rerun
+// it.
+
+// Runs after a migration's sql, in the same transaction, for migrations that need to fill in data.
+type MigrationHook = fn(&Connection) -> Result<(), CommonplaceError>;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    sql: &'static str,
+    after: Option<MigrationHook>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        sql: include_str!("setup.sql"),
+        after: None,
+    },
+    Migration {
+        version: 2,
+        name: "full text search",
+        sql: include_str!("migrations/002_notes_fts.sql"),
+        after: Some(rebuild_search_index),
+    },
+    Migration {
+        version: 3,
+        name: "note tombstones",
+        sql: include_str!("migrations/003_tombstones.sql"),
+        after: None,
+    },
+    Migration {
+        version: 4,
+        name: "hidden tags",
+        sql: include_str!("migrations/004_hidden_tags.sql"),
+        after: None,
+    },
+    Migration {
+        version: 5,
+        name: "links",
+        sql: include_str!("migrations/005_links.sql"),
+        after: None,
+    },
+    Migration {
+        version: 6,
+        name: "link anchors",
+        sql: include_str!("migrations/006_link_anchors.sql"),
+        after: None,
+    },
+    Migration {
+        version: 7,
+        name: "cards",
+        sql: include_str!("migrations/007_cards.sql"),
+        after: None,
+    },
+    Migration {
+        version: 8,
+        name: "card identity",
+        sql: include_str!("migrations/008_card_identity.sql"),
+        after: Some(sync_all_cards),
+    },
+    Migration {
+        version: 9,
+        name: "note modification times",
+        sql: include_str!("migrations/009_note_modified.sql"),
+        after: None,
+    },
+    Migration {
+        version: 10,
+        name: "sync peers",
+        sql: include_str!("migrations/010_sync_peers.sql"),
+        after: None,
+    },
+    Migration {
+        version: 11,
+        name: "conflicts",
+        sql: include_str!("migrations/011_conflicts.sql"),
+        after: None,
+    },
+    Migration {
+        version: 12,
+        name: "hybrid logical clocks",
+        sql: include_str!("migrations/012_clocks.sql"),
+        after: Some(init_clock),
+    },
+    Migration {
+        version: 13,
+        name: "operation log",
+        sql: include_str!("migrations/013_operation_log.sql"),
+        after: None,
+    },
+    Migration {
+        version: 14,
+        name: "remove spurious note history",
+        sql: include_str!("migrations/014_spurious_history.sql"),
+        after: None,
+    },
+    Migration {
+        version: 15,
+        name: "link tombstones",
+        sql: include_str!("migrations/015_link_tombstones.sql"),
+        after: None,
+    },
+];
+
+pub fn latest_schema_version() -> u32 {
+    MIGRATIONS.last().map(|x| x.version).unwrap_or(0)
+}
+
+pub fn get_schema_version(db: &Connection) -> Result<u32, CommonplaceError> {
+    let version: u32 = db.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    if version == 0 {
+        // Repositories created before migrations existed have the initial schema, but no version.
+        let has_tables: bool = db.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'Notes'",
+            params![],
+            |row| row.get(0)
+        )?;
+        if has_tables {
+            return Ok(1);
+        }
+    }
+    Ok(version)
+}
+
+pub fn pending_migrations(db: &Connection) -> Result<Vec<&'static Migration>, CommonplaceError> {
+    let version = get_schema_version(db)?;
+    if version > latest_schema_version() {
+        return Err(CommonplaceError::UnsupportedSchemaVersion(version));
+    }
+    Ok(MIGRATIONS.iter().filter(|x| x.version > version).collect())
+}
+
+pub fn migrate_db(db: &Connection) -> Result<Vec<&'static Migration>, CommonplaceError> {
+    let pending = pending_migrations(db)?;
+    for migration in &pending {
+        let tx = db.unchecked_transaction()?;
+        tx.execute_batch(migration.sql)?;
+        if let Some(after) = migration.after {
+            after(&tx)?;
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {}", migration.version))?;
+        tx.commit()?;
+    }
+    Ok(pending)
+}
@@ -0,0 +1,9 @@
+-- Full text index over the current name and contents of every text/* note.
+-- This is kept up to date by libcommonplace rather than by triggers, since
+-- the blobs need to be decoded before they can be indexed. (IF NOT EXISTS
+-- because some repositories got this table before migrations existed.)
+CREATE VIRTUAL TABLE IF NOT EXISTS NotesFts USING fts5(
+	note_id UNINDEXED, -- UUID4
+	name,
+	contents
+);
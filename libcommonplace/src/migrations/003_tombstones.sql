@@ -0,0 +1,8 @@
+-- When a note is deleted its tags and history are deleted along with it, but
+-- we keep its UUID here so that syncing with a repository that still has the
+-- note doesn't bring it back. The blobs it pointed to are left alone, and are
+-- cleaned up by garbage collection if nothing else refers to them.
+CREATE TABLE Tombstones(
+	id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	time INTEGER NOT NULL -- UTC epoch time
+);
@@ -0,0 +1,7 @@
+-- Notes tagged with any of these tags, or any of their descendants, are left
+-- out of the standard views unless explicitly asked for. This is how archiving
+-- works: tagging a note "archive" hides it while keeping all its other tags.
+CREATE TABLE HiddenTags(
+	tag_id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	FOREIGN KEY(tag_id) REFERENCES Tags(id) ON DELETE CASCADE
+);
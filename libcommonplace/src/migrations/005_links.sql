@@ -0,0 +1,20 @@
+-- Directed links from a note to another note (to_note) or to something
+-- outside of the repository (to_url) — exactly one of the two is set. Links
+-- are mostly used for annotation, where the note doing the linking is about
+-- the thing it links to. from_start and from_end optionally narrow the link to
+-- a byte range in the current contents of from_note.
+CREATE TABLE Links(
+	id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	from_note BLOB NOT NULL, -- UUID4
+	from_start INTEGER DEFAULT NULL,
+	from_end INTEGER DEFAULT NULL,
+	to_note BLOB DEFAULT NULL, -- UUID4
+	to_url TEXT DEFAULT NULL,
+	kind TEXT NOT NULL,
+	FOREIGN KEY(from_note) REFERENCES Notes(id) ON DELETE CASCADE,
+	FOREIGN KEY(to_note) REFERENCES Notes(id) ON DELETE CASCADE,
+	CHECK ((to_note IS NULL) != (to_url IS NULL))
+);
+
+CREATE INDEX LinksFromNote ON Links(from_note);
+CREATE INDEX LinksToNote ON Links(to_note);
@@ -0,0 +1,34 @@
+-- Links get an anchor at each end (see anchor.rs in libcommonplace_types for
+-- the format), which replaces the byte range that the linking note used to
+-- have. Existing ranges become text anchors. SQLite can't drop columns, so
+-- the table is rebuilt.
+CREATE TABLE LinksWithAnchors(
+	id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	from_note BLOB NOT NULL, -- UUID4
+	from_anchor TEXT DEFAULT NULL,
+	to_note BLOB DEFAULT NULL, -- UUID4
+	to_url TEXT DEFAULT NULL,
+	to_anchor TEXT DEFAULT NULL,
+	kind TEXT NOT NULL,
+	FOREIGN KEY(from_note) REFERENCES Notes(id) ON DELETE CASCADE,
+	FOREIGN KEY(to_note) REFERENCES Notes(id) ON DELETE CASCADE,
+	CHECK ((to_note IS NULL) != (to_url IS NULL)),
+	CHECK (to_url IS NULL OR to_anchor IS NULL)
+);
+
+INSERT INTO LinksWithAnchors (rowid, id, from_note, from_anchor, to_note, to_url, kind)
+SELECT
+	rowid,
+	id,
+	from_note,
+	CASE WHEN from_start IS NULL THEN NULL ELSE 'text:' || from_start || '-' || from_end END,
+	to_note,
+	to_url,
+	kind
+FROM Links;
+
+DROP TABLE Links;
+ALTER TABLE LinksWithAnchors RENAME TO Links;
+
+CREATE INDEX LinksFromNote ON Links(from_note);
+CREATE INDEX LinksToNote ON Links(to_note);
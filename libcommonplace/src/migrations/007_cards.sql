@@ -0,0 +1,31 @@
+-- Spaced repetition cards, extracted from the contents of text notes (see
+-- cards.rs in libcommonplace_types for the syntax). These are kept in sync
+-- with the notes by libcommonplace, like NotesFts, but unlike NotesFts they
+-- have state of their own — the SM-2 schedule — so they can't just be
+-- rebuilt from scratch.
+CREATE TABLE Cards(
+	id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	note_id BLOB NOT NULL, -- UUID4
+	front TEXT NOT NULL,
+	back TEXT NOT NULL,
+	ease REAL NOT NULL,
+	interval INTEGER NOT NULL, -- days
+	repetitions INTEGER NOT NULL,
+	due INTEGER NOT NULL, -- UTC epoch time
+	FOREIGN KEY(note_id) REFERENCES Notes(id) ON DELETE CASCADE
+);
+
+CREATE INDEX CardsNote ON Cards(note_id);
+CREATE INDEX CardsDue ON Cards(due);
+
+-- Every review of every card, with the schedule that the review produced.
+CREATE TABLE ReviewLog(
+	card_id BLOB NOT NULL, -- UUID4
+	time INTEGER NOT NULL, -- UTC epoch time
+	grade INTEGER NOT NULL, -- SM-2 quality, 0-5
+	ease REAL NOT NULL,
+	interval INTEGER NOT NULL, -- days
+	FOREIGN KEY(card_id) REFERENCES Cards(id) ON DELETE CASCADE
+);
+
+CREATE INDEX ReviewLogCard ON ReviewLog(card_id);
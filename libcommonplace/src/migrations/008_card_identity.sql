@@ -0,0 +1,11 @@
+-- Cards whose text disappears from their note are flagged rather than
+-- deleted, so that their reviews aren't lost. The review log remembers what
+-- the card said when it was reviewed, so that cards that have changed since
+-- can be found. Existing reviews are assumed to be of the current text.
+ALTER TABLE Cards ADD COLUMN orphaned INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE ReviewLog ADD COLUMN front TEXT NOT NULL DEFAULT '';
+ALTER TABLE ReviewLog ADD COLUMN back TEXT NOT NULL DEFAULT '';
+
+UPDATE ReviewLog SET
+	front = (SELECT front FROM Cards WHERE Cards.id = ReviewLog.card_id),
+	back = (SELECT back FROM Cards WHERE Cards.id = ReviewLog.card_id);
@@ -0,0 +1,22 @@
+-- When the current version of each note was written, so that syncing can tell
+-- which of two conflicting versions is newer. For existing notes that's when
+-- the version before it was replaced, or never (0) if it's the first version.
+ALTER TABLE Notes ADD COLUMN modified INTEGER NOT NULL DEFAULT 0; -- UTC epoch time
+
+UPDATE Notes SET modified = COALESCE((SELECT MAX(time) FROM NoteHistory WHERE note_id = Notes.id), 0);
+
+-- Only changes to the note itself are history, not changes to when it was
+-- modified.
+DROP TRIGGER note_update_history;
+CREATE TRIGGER note_update_history
+AFTER UPDATE OF hash, name, mimetype ON Notes
+BEGIN
+	INSERT INTO NoteHistory (note_id, hash, name, mimetype, time)
+	VALUES (
+		old.id,
+		old.hash,
+		old.name,
+		old.mimetype,
+		strftime('%s', 'now')
+	);
+END;
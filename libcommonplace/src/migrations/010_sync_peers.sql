@@ -0,0 +1,68 @@
+-- NoteHistory gets an explicit id, so that incremental bundles can send just
+-- the history added since the last one a peer acknowledged. AUTOINCREMENT
+-- makes sure ids aren't reused after a note's history is deleted.
+DROP TRIGGER note_update_history;
+
+CREATE TABLE NoteHistoryWithIds(
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	note_id BLOB NOT NULL, -- UUID4
+	hash BLOB NOT NULL, -- blake3
+	name TEXT NOT NULL,
+	mimetype TEXT NOT NULL,
+	time INTEGER NOT NULL, -- UTC epoch time
+	FOREIGN KEY(note_id) REFERENCES Notes(id) ON DELETE CASCADE
+);
+
+INSERT INTO NoteHistoryWithIds (id, note_id, hash, name, mimetype, time)
+SELECT rowid, note_id, hash, name, mimetype, time FROM NoteHistory;
+
+DROP TABLE NoteHistory;
+ALTER TABLE NoteHistoryWithIds RENAME TO NoteHistory;
+
+CREATE TRIGGER note_update_history
+AFTER UPDATE OF hash, name, mimetype ON Notes
+BEGIN
+	INSERT INTO NoteHistory (note_id, hash, name, mimetype, time)
+	VALUES (
+		old.id,
+		old.hash,
+		old.name,
+		old.mimetype,
+		strftime('%s', 'now')
+	);
+END;
+
+-- Other repositories that bundles are exchanged with, by whatever name the
+-- user gave them. A peer is known to have every NoteHistory row up to
+-- history_id, and every blob in PeerBlobs, so those don't need to be sent to
+-- it again. received_bundle is the last bundle imported from the peer, which
+-- is acknowledged in the next bundle exported for it.
+CREATE TABLE Peers(
+	name TEXT PRIMARY KEY NOT NULL,
+	history_id INTEGER NOT NULL DEFAULT 0,
+	received_bundle BLOB DEFAULT NULL -- UUID4
+);
+
+CREATE TABLE PeerBlobs(
+	peer TEXT NOT NULL,
+	hash BLOB NOT NULL, -- blake3
+	PRIMARY KEY(peer, hash),
+	FOREIGN KEY(peer) REFERENCES Peers(name) ON DELETE CASCADE
+);
+
+-- Bundles exported for a peer that it hasn't acknowledged yet, and the blobs
+-- it will have once it imports them.
+CREATE TABLE SentBundles(
+	id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	peer TEXT NOT NULL,
+	history_id INTEGER NOT NULL,
+	time INTEGER NOT NULL, -- UTC epoch time
+	FOREIGN KEY(peer) REFERENCES Peers(name) ON DELETE CASCADE
+);
+
+CREATE TABLE SentBlobs(
+	bundle_id BLOB NOT NULL, -- UUID4
+	hash BLOB NOT NULL, -- blake3
+	PRIMARY KEY(bundle_id, hash),
+	FOREIGN KEY(bundle_id) REFERENCES SentBundles(id) ON DELETE CASCADE
+);
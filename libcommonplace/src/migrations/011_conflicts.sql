@@ -0,0 +1,12 @@
+-- Notes that were edited on two devices in ways that couldn't be merged. The
+-- note keeps whichever head was modified last, and both heads are kept here
+-- along with the version they were both edited from, until the conflict is
+-- resolved. A note has at most one conflict — a newer one replaces it.
+CREATE TABLE Conflicts(
+	note_id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	base BLOB NOT NULL, -- blake3
+	ours BLOB NOT NULL, -- blake3, the head this repository had
+	theirs BLOB NOT NULL, -- blake3, the head that was imported
+	time INTEGER NOT NULL, -- UTC epoch time
+	FOREIGN KEY(note_id) REFERENCES Notes(id) ON DELETE CASCADE
+);
@@ -0,0 +1,68 @@
+-- Changes are stamped with a hybrid logical clock timestamp and the device
+-- that made them, so that changes made on different devices are put in the
+-- same order everywhere, instead of by one-second wall clock times that may
+-- not agree with each other. A timestamp (hlc) is milliseconds since the
+-- epoch shifted left 16 bits, plus a counter, and device is the UUID of the
+-- repository that made the change. Clock has this repository's device id, and
+-- the last timestamp it gave out; its row is filled in by the migration.
+CREATE TABLE Clock(
+	device BLOB NOT NULL, -- UUID4
+	hlc INTEGER NOT NULL
+);
+
+-- Existing timestamps are converted from seconds, and have no device.
+ALTER TABLE Notes RENAME COLUMN modified TO hlc;
+UPDATE Notes SET hlc = (hlc * 1000) << 16;
+ALTER TABLE Notes ADD COLUMN device BLOB DEFAULT NULL; -- UUID4
+
+ALTER TABLE Tombstones ADD COLUMN hlc INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Tombstones ADD COLUMN device BLOB DEFAULT NULL; -- UUID4
+UPDATE Tombstones SET hlc = (time * 1000) << 16;
+
+-- Each version in the history keeps the timestamp it was written with. time is
+-- still when it was replaced, by this repository's wall clock.
+ALTER TABLE NoteHistory ADD COLUMN hlc INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE NoteHistory ADD COLUMN device BLOB DEFAULT NULL; -- UUID4
+
+DROP TRIGGER note_update_history;
+CREATE TRIGGER note_update_history
+AFTER UPDATE OF hash, name, mimetype ON Notes
+BEGIN
+	INSERT INTO NoteHistory (note_id, hash, name, mimetype, time, hlc, device)
+	VALUES (
+		old.id,
+		old.hash,
+		old.name,
+		old.mimetype,
+		strftime('%s', 'now'),
+		old.hlc,
+		old.device
+	);
+END;
+
+-- When a tag was created, or last renamed or moved.
+ALTER TABLE Tags ADD COLUMN hlc INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Tags ADD COLUMN device BLOB DEFAULT NULL; -- UUID4
+
+-- When a note was tagged.
+ALTER TABLE TagMap ADD COLUMN hlc INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE TagMap ADD COLUMN device BLOB DEFAULT NULL; -- UUID4
+
+-- Deleted tags, and their descendants, which are deleted along with them. Like
+-- note tombstones, these keep syncing from bringing the tags back.
+CREATE TABLE TagTombstones(
+	id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	hlc INTEGER NOT NULL,
+	device BLOB DEFAULT NULL -- UUID4
+);
+
+-- Notes that were untagged, so that syncing can tell whether a note was
+-- untagged after it was tagged on another device, or before. A (note, tag)
+-- pair is either in TagMap or here, whichever happened last.
+CREATE TABLE TagMapTombstones(
+	note_id BLOB NOT NULL, -- UUID4
+	tag_id BLOB NOT NULL, -- UUID4
+	hlc INTEGER NOT NULL,
+	device BLOB DEFAULT NULL, -- UUID4
+	PRIMARY KEY(note_id, tag_id)
+);
@@ -0,0 +1,27 @@
+-- Every change made to this repository, as JSON-serialized Operations, with
+-- the timestamp it was stamped with. note_id is the note the operation is
+-- about, if any, so that a note's own timeline can be looked up. Card reviews
+-- aren't in here, since they're in ReviewLog.
+CREATE TABLE OperationLog(
+	hlc INTEGER NOT NULL,
+	device BLOB NOT NULL, -- UUID4
+	note_id BLOB DEFAULT NULL, -- UUID4
+	operation TEXT NOT NULL,
+	PRIMARY KEY(hlc, device)
+);
+
+CREATE INDEX OperationLogNotes ON OperationLog(note_id);
+
+-- The log is append-only, and isn't cleaned up when a note is deleted, so that
+-- deleting can be undone.
+CREATE TRIGGER operation_log_no_update
+BEFORE UPDATE ON OperationLog
+BEGIN
+	SELECT RAISE(ABORT, 'the operation log is append-only');
+END;
+
+CREATE TRIGGER operation_log_no_delete
+BEFORE DELETE ON OperationLog
+BEGIN
+	SELECT RAISE(ABORT, 'the operation log is append-only');
+END;
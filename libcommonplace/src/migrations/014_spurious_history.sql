@@ -0,0 +1,13 @@
+-- Until migration 9 was fixed, it filled in modified while the old history
+-- trigger was still installed, which added a copy of the current version of
+-- every note to its history. Those copies are the same as the version after
+-- them, and were written before history had timestamps (hlc = 0), so they're
+-- removed here. modified itself was filled in before they were written, so it
+-- (and the hlc it became) doesn't need to be recomputed.
+DELETE FROM NoteHistory WHERE rowid IN (
+	SELECT h.rowid FROM NoteHistory h JOIN Notes n ON n.id = h.note_id
+	WHERE h.hlc = 0
+	AND h.hash = COALESCE((SELECT hash FROM NoteHistory WHERE note_id = h.note_id AND rowid > h.rowid ORDER BY rowid LIMIT 1), n.hash)
+	AND h.name = COALESCE((SELECT name FROM NoteHistory WHERE note_id = h.note_id AND rowid > h.rowid ORDER BY rowid LIMIT 1), n.name)
+	AND h.mimetype = COALESCE((SELECT mimetype FROM NoteHistory WHERE note_id = h.note_id AND rowid > h.rowid ORDER BY rowid LIMIT 1), n.mimetype)
+);
@@ -0,0 +1,8 @@
+-- Deleted links, so that syncing doesn't bring them back from repositories
+-- that still have them. Links are never changed once they're added, so like
+-- note tombstones, these always win.
+CREATE TABLE LinkTombstones(
+	id BLOB PRIMARY KEY NOT NULL, -- UUID4
+	hlc INTEGER NOT NULL,
+	device BLOB DEFAULT NULL -- UUID4
+);
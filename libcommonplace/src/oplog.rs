@@ -0,0 +1,32 @@
+use rusqlite::params;
+use uuid::Uuid;
+use crate::{Connection, CommonplaceError, LogEntry, Operation, Timestamp};
+use crate::clock::timestamp_from_row;
+
+// Every function that changes the repository records what it did here, stamped with the same
+// timestamp as the change itself, so the log can be read as a timeline of the repository.
+
+pub(crate) fn log_operation(db: &Connection, time: &Timestamp, operation: &Operation) -> Result<(), CommonplaceError> {
+    db.execute(
+        "INSERT INTO OperationLog (hlc, device, note_id, operation) VALUES (?1, ?2, ?3, ?4)",
+        params![time.hlc, time.device, operation.note().map(|x| x.uuid), serde_json::to_string(operation).unwrap()]
+    )?;
+    Ok(())
+}
+
+fn log_entry_from_row(row: &rusqlite::Row) -> Result<LogEntry, rusqlite::Error> {
+    let operation: String = row.get("operation")?;
+    Ok(LogEntry {
+        time: timestamp_from_row(row)?,
+        operation: serde_json::from_str(&operation).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?,
+    })
+}
+
+// The whole log, or just the operations on one note, oldest first.
+pub fn get_operation_log(db: &Connection, note: Option<Uuid>) -> Result<Vec<LogEntry>, CommonplaceError> {
+    let mut query = db.prepare("SELECT * FROM OperationLog WHERE ?1 IS NULL OR note_id = ?1 ORDER BY hlc, device")?;
+    let log = query.query_map(params![note], log_entry_from_row)?.collect::<Result<Vec<LogEntry>, rusqlite::Error>>()?;
+    Ok(log)
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::CommonplaceError;
+
+// A repository is a single sqlite database. It's found by looking at, in order:
+//
+// * The --repo flag passed to a binary
+// * The COMMONPLACE_REPO environment variable
+// * An index.db file in the current directory or any of its parents
+// * The repository named "default" in the config file
+//
+// --repo and COMMONPLACE_REPO can either be the name of a repository from the config file, a
+// directory containing an index.db, or the path to a database file.
+//
+// The config file is at $COMMONPLACE_CONFIG, $XDG_CONFIG_HOME/commonplace/repos, or
+// ~/.config/commonplace/repos, and has one `name = path` line per repository. Relative paths are
+// relative to the config file.
+
+pub const REPO_FILENAME: &str = "index.db";
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("COMMONPLACE_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("commonplace").join("repos"))
+}
+
+pub fn read_repo_config() -> Result<HashMap<String, PathBuf>, CommonplaceError> {
+    let mut repos = HashMap::new();
+    let path = match config_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(repos),
+    };
+    let base = path.parent().unwrap_or_else(|| Path::new("/")).to_path_buf();
+
+    for line in fs::read_to_string(&path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(i) = line.find('=') {
+            let name = line[..i].trim().to_string();
+            let repo = base.join(line[i + 1..].trim());
+            repos.insert(name, repo);
+        }
+    }
+
+    Ok(repos)
+}
+
+fn repo_file(path: PathBuf) -> PathBuf {
+    if path.is_dir() {
+        path.join(REPO_FILENAME)
+    } else {
+        path
+    }
+}
+
+fn resolve_repo(repo: &str) -> Result<PathBuf, CommonplaceError> {
+    match read_repo_config()?.remove(repo) {
+        Some(path) => Ok(repo_file(path)),
+        None => Ok(repo_file(PathBuf::from(repo))),
+    }
+}
+
+// Opening a repository that doesn't exist would create an empty one, so only new_repo_path returns
+// paths that don't exist yet.
+fn existing(path: PathBuf) -> Result<PathBuf, CommonplaceError> {
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(CommonplaceError::RepositoryNotFound)
+    }
+}
+
+pub fn find_repo(repo: Option<&str>) -> Result<PathBuf, CommonplaceError> {
+    if let Some(repo) = repo {
+        return existing(resolve_repo(repo)?);
+    }
+    if let Ok(repo) = env::var("COMMONPLACE_REPO") {
+        return existing(resolve_repo(&repo)?);
+    }
+    for dir in env::current_dir()?.ancestors() {
+        let path = dir.join(REPO_FILENAME);
+        if path.is_file() {
+            return Ok(path);
+        }
+    }
+    match read_repo_config()?.remove("default") {
+        Some(path) => existing(repo_file(path)),
+        None => Err(CommonplaceError::RepositoryNotFound),
+    }
+}
+
+// Where a new repository should be created: like find_repo, but falling back to the current
+// directory rather than searching for an existing repository.
+pub fn new_repo_path(repo: Option<&str>) -> Result<PathBuf, CommonplaceError> {
+    if let Some(repo) = repo {
+        return resolve_repo(repo);
+    }
+    if let Ok(repo) = env::var("COMMONPLACE_REPO") {
+        return resolve_repo(&repo);
+    }
+    Ok(env::current_dir()?.join(REPO_FILENAME))
+}
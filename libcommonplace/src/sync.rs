@@ -0,0 +1,865 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use rusqlite::{params, OptionalExtension};
+use uuid::Uuid;
+use crate::{Connection, CommonplaceError, Inventory, Link, NoteId, Operation, TagId, Timestamp, latest_schema_version, tick, log_operation, merge_lines, add_bytes_to_blobstore, delete_note_internal, delete_tag_internal, check_tag_name_free, has_ancestor, index_note_contents, sync_cards, get_note, get_tag_row, link_from_row, hash_from_bytes};
+use crate::clock::{observe, timestamp_from_row};
+
+// A bundle is a standalone sqlite database with a copy of a repository's tables, for syncing
+// repositories that can't talk to each other directly: export a bundle from one, carry it over,
+// and import it into the other. Importing merges the two repositories:
+//
+// * Blobs, history, tags, links and tombstones are unioned. Tags with the same name under the same
+//   parent are the same tag, even if they were created separately.
+// * Tombstones win, so a note, tag or link deleted on either side ends up deleted on both.
+// * Renaming or moving a tag, and tagging or untagging a note, are last-write-wins, by the
+//   timestamps from clock.rs.
+// * When a note has changed on only one side, which is the case when the other side's head is in
+//   its history, the changed version wins.
+// * When a text note has changed on both sides, the changes are merged with merge_lines, using the
+//   newest version in the bundle's history of the note that's also in ours as the base.
+// * Otherwise, or if the changes overlap, the version that was modified last becomes the current
+//   one, and the other is kept in the note's history. If they overlap, both heads and the base are
+//   recorded in Conflicts.
+//
+// Cards and reviews aren't included, since they're per-device; cards are re-extracted from the
+// notes that changed. The bundle's user_version is the schema version of the repository it came
+// from, and only bundles from the same schema version can be imported.
+//
+// Bundles exported for a named peer are incremental: they leave out the history and blobs that the
+// peer is known to have, either because it sent them to us or because it acknowledged a bundle
+// that had them. Each bundle acknowledges the last bundle imported from the peer it's for. Until a
+// bundle is acknowledged, the next one for the same peer includes everything it did, so a lost
+// bundle doesn't lose anything. The rest of the tables are small, and are always sent whole.
+//
+// Repositories that can reach each other over the network don't need to keep track of each other,
+// since they can just swap inventories of what they have, and send bundles that leave out whatever
+// the other side's inventory lists.
+//
+// Besides the repository's tables, a bundle has:
+//
+// * Bundle, with the bundle's id.
+// * Acks, with the id of the bundle it acknowledges, if any.
+// * Manifest, with the hash of every version of every note in the bundle. Each of them has to
+//   either be in the bundle or already be in the repository it's imported into.
+// * Lineage, with the note, hash, time and id of every row of the exporting repository's history,
+//   even the rows that were left out, so that merging can find the base of both sides' changes.
+
+const BUNDLE_TABLES: &[&str] = &["Notes", "Tags", "TagMap", "HiddenTags", "Links", "Tombstones", "TagTombstones", "TagMapTombstones", "LinkTombstones"];
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub blobs: usize,
+    // Versions of notes added to their history, besides the ones replaced by updates.
+    pub history: usize,
+    pub tags: usize,
+    pub notes_added: usize,
+    pub notes_updated: usize,
+    pub notes_deleted: usize,
+    pub notes_merged: usize,
+    pub conflicts: usize,
+    // Everything the import changed, in order, as it's recorded in the operation log.
+    pub changes: Vec<Operation>,
+}
+
+#[derive(Debug)]
+pub struct Peer {
+    pub name: String,
+    // How many blobs the peer is known to have.
+    pub blobs: usize,
+    // Bundles exported for the peer that it hasn't acknowledged yet.
+    pub unacknowledged: usize,
+}
+
+// Attaches the bundle as the "bundle" schema for the length of f.
+fn with_bundle<T, F>(db: &Connection, path: &Path, f: F) -> Result<T, CommonplaceError>
+    where F: FnOnce(&Connection) -> Result<T, CommonplaceError>
+{
+    db.execute("ATTACH DATABASE ?1 AS bundle", params![path.to_string_lossy()])?;
+    let res = f(db);
+    db.execute("DETACH DATABASE bundle", params![])?;
+    res
+}
+
+// With a peer, the bundle is incremental, and is remembered until the peer acknowledges it.
+pub fn export_bundle(db: &Connection, path: &Path, peer: Option<&str>) -> Result<(), CommonplaceError> {
+    write_bundle(db, path, peer, &Inventory::default())
+}
+
+// For syncing with a repository directly, rather than through a peer: leaves out the blobs and
+// history that it says it has.
+pub fn export_bundle_for(db: &Connection, path: &Path, inventory: &Inventory) -> Result<(), CommonplaceError> {
+    write_bundle(db, path, None, inventory)
+}
+
+pub fn get_inventory(db: &Connection) -> Result<Inventory, CommonplaceError> {
+    let hash = |row: &rusqlite::Row, column: usize| -> Result<[u8; 32], rusqlite::Error> {
+        let mut hash: [u8; 32] = [0; 32];
+        hash.copy_from_slice(&row.get::<usize, Vec<u8>>(column)?[..]);
+        Ok(hash)
+    };
+    let mut blob_query = db.prepare("SELECT hash FROM Blobs")?;
+    let blobs = blob_query.query_map(params![], |row| hash(row, 0))?.collect::<Result<Vec<[u8; 32]>, rusqlite::Error>>()?;
+    let mut history_query = db.prepare("SELECT note_id, hash, time FROM NoteHistory")?;
+    let history = history_query.query_map(params![], |row| {
+        Ok((NoteId { uuid: row.get(0)? }, hash(row, 1)?, row.get(2)?))
+    })?.collect::<Result<Vec<(NoteId, [u8; 32], i64)>, rusqlite::Error>>()?;
+    Ok(Inventory { blobs, history })
+}
+
+fn write_bundle(db: &Connection, path: &Path, peer: Option<&str>, inventory: &Inventory) -> Result<(), CommonplaceError> {
+    if path.exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path.display())).into());
+    }
+    with_bundle(db, path, |db| {
+        let tx = db.unchecked_transaction()?;
+        if let Some(peer) = peer {
+            tx.execute("INSERT OR IGNORE INTO Peers (name) VALUES (?1)", params![peer])?;
+        }
+        let (history_id, acked): (i64, Option<Uuid>) = tx.query_row(
+            "SELECT history_id, received_bundle FROM Peers WHERE name = ?1",
+            params![peer],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).optional()?.unwrap_or((0, None));
+
+        let id = Uuid::new_v4();
+        tx.execute_batch("CREATE TABLE bundle.Bundle (id BLOB NOT NULL); CREATE TABLE bundle.Acks (id BLOB NOT NULL);")?;
+        tx.execute("INSERT INTO bundle.Bundle (id) VALUES (?1)", params![id])?;
+        if let Some(acked) = acked {
+            tx.execute("INSERT INTO bundle.Acks (id) VALUES (?1)", params![acked])?;
+        }
+
+        tx.execute_batch(
+            "CREATE TEMP TABLE TheirBlobs (hash BLOB PRIMARY KEY NOT NULL);
+             CREATE TEMP TABLE TheirHistory (note_id BLOB NOT NULL, hash BLOB NOT NULL, time INTEGER NOT NULL);"
+        )?;
+        for hash in &inventory.blobs {
+            tx.execute("INSERT OR IGNORE INTO temp.TheirBlobs (hash) VALUES (?1)", params![hash.to_vec()])?;
+        }
+        for (note, hash, time) in &inventory.history {
+            tx.execute("INSERT INTO temp.TheirHistory (note_id, hash, time) VALUES (?1, ?2, ?3)", params![note.uuid, hash.to_vec(), time])?;
+        }
+
+        for table in BUNDLE_TABLES {
+            tx.execute_batch(&format!("CREATE TABLE bundle.{0} AS SELECT * FROM main.{0}", table))?;
+        }
+        tx.execute(
+            "CREATE TABLE bundle.NoteHistory AS SELECT * FROM main.NoteHistory AS h
+             WHERE id > ?1
+             AND NOT EXISTS (SELECT 1 FROM temp.TheirHistory AS t WHERE t.note_id = h.note_id AND t.hash = h.hash AND t.time = h.time)",
+            params![history_id]
+        )?;
+        tx.execute_batch(
+            "CREATE TABLE bundle.Lineage AS SELECT id, note_id, hash, time FROM main.NoteHistory;
+             CREATE TABLE bundle.Manifest AS
+             SELECT id AS note_id, hash FROM bundle.Notes UNION SELECT note_id, hash FROM bundle.NoteHistory"
+        )?;
+        tx.execute(
+            "CREATE TABLE bundle.Blobs AS SELECT * FROM main.Blobs
+             WHERE hash IN (SELECT hash FROM bundle.Manifest)
+             AND hash NOT IN (SELECT hash FROM main.PeerBlobs WHERE peer = ?1)
+             AND hash NOT IN (SELECT hash FROM temp.TheirBlobs)",
+            params![peer]
+        )?;
+        tx.execute_batch("DROP TABLE temp.TheirBlobs; DROP TABLE temp.TheirHistory;")?;
+        tx.execute_batch(&format!("PRAGMA bundle.user_version = {}", latest_schema_version()))?;
+
+        if let Some(peer) = peer {
+            tx.execute(
+                "INSERT INTO SentBundles (id, peer, history_id, time)
+                 VALUES (?1, ?2, (SELECT COALESCE(MAX(id), 0) FROM main.NoteHistory), strftime('%s', 'now'))",
+                params![id, peer]
+            )?;
+            tx.execute("INSERT INTO SentBlobs (bundle_id, hash) SELECT DISTINCT ?1, hash FROM bundle.Manifest", params![id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    })
+}
+
+// With a peer, the bundle's acknowledgement is recorded, along with everything the peer has.
+pub fn import_bundle(db: &Connection, path: &Path, peer: Option<&str>) -> Result<ImportReport, CommonplaceError> {
+    // Attaching a file that doesn't exist would create an empty database.
+    if !path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist", path.display())).into());
+    }
+    with_bundle(db, path, |db| {
+        let version: u32 = db.query_row("PRAGMA bundle.user_version", params![], |row| row.get(0))?;
+        if version != latest_schema_version() {
+            return Err(CommonplaceError::UnsupportedBundleVersion(version));
+        }
+        let problems = verify_bundle(db)?;
+        if problems > 0 {
+            return Err(CommonplaceError::IncompleteBundle(problems));
+        }
+
+        let tx = db.unchecked_transaction()?;
+        let report = import_bundle_internal(&tx)?;
+        if !report.changes.is_empty() || report.history > 0 || report.blobs > 0 {
+            let operation = Operation::ImportBundle {
+                notes_added: report.notes_added,
+                notes_updated: report.notes_updated,
+                notes_merged: report.notes_merged,
+                notes_deleted: report.notes_deleted,
+                tags: report.tags,
+                conflicts: report.conflicts,
+                history: report.history,
+                blobs: report.blobs,
+                changes: report.changes.clone(),
+            };
+            log_operation(&tx, &tick(&tx)?, &operation)?;
+        }
+        if let Some(peer) = peer {
+            record_peer_bundle(&tx, peer)?;
+        }
+        tx.commit()?;
+        Ok(report)
+    })
+}
+
+// The number of blobs that the bundle needs but doesn't have, or that don't match their hash.
+// Versions of notes that have been deleted on either side aren't needed.
+fn verify_bundle(db: &Connection) -> Result<usize, CommonplaceError> {
+    let missing: i64 = db.query_row(
+        "SELECT COUNT(DISTINCT hash) FROM bundle.Manifest
+         WHERE note_id NOT IN (SELECT id FROM main.Tombstones)
+         AND note_id NOT IN (SELECT id FROM bundle.Tombstones)
+         AND hash NOT IN (SELECT hash FROM bundle.Blobs)
+         AND hash NOT IN (SELECT hash FROM main.Blobs)",
+        params![],
+        |row| row.get(0)
+    )?;
+
+    let mut corrupt = 0;
+    let mut query = db.prepare("SELECT hash, contents FROM bundle.Blobs")?;
+    let mut rows = query.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let hash: Vec<u8> = row.get(0)?;
+        let contents: Vec<u8> = row.get(1)?;
+        if blake3::hash(&contents).as_bytes()[..] != hash[..] {
+            corrupt += 1;
+        }
+    }
+
+    Ok(missing as usize + corrupt)
+}
+
+fn record_peer_bundle(db: &Connection, peer: &str) -> Result<(), CommonplaceError> {
+    db.execute("INSERT OR IGNORE INTO Peers (name) VALUES (?1)", params![peer])?;
+
+    // Everything up to the acknowledged bundle has made it to the peer, and older bundles don't
+    // need to be remembered, since the acknowledged one had everything they did.
+    let acked: Option<(i64, i64)> = db.query_row(
+        "SELECT rowid, history_id FROM SentBundles WHERE peer = ?1 AND id IN (SELECT id FROM bundle.Acks)",
+        params![peer],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).optional()?;
+    if let Some((rowid, history_id)) = acked {
+        db.execute(
+            "INSERT OR IGNORE INTO PeerBlobs (peer, hash)
+             SELECT ?1, hash FROM SentBlobs WHERE bundle_id IN (SELECT id FROM SentBundles WHERE peer = ?1 AND rowid <= ?2)",
+            params![peer, rowid]
+        )?;
+        db.execute("UPDATE Peers SET history_id = MAX(history_id, ?1) WHERE name = ?2", params![history_id, peer])?;
+        db.execute("DELETE FROM SentBundles WHERE peer = ?1 AND rowid <= ?2", params![peer, rowid])?;
+    }
+
+    // The peer has everything it sent us.
+    db.execute("INSERT OR IGNORE INTO PeerBlobs (peer, hash) SELECT DISTINCT ?1, hash FROM bundle.Manifest", params![peer])?;
+    db.execute("UPDATE Peers SET received_bundle = (SELECT id FROM bundle.Bundle) WHERE name = ?1", params![peer])?;
+    Ok(())
+}
+
+pub fn get_peers(db: &Connection) -> Result<Vec<Peer>, CommonplaceError> {
+    let mut query = db.prepare(
+        "SELECT name,
+         (SELECT COUNT(*) FROM PeerBlobs WHERE peer = name) AS blobs,
+         (SELECT COUNT(*) FROM SentBundles WHERE peer = name) AS unacknowledged
+         FROM Peers ORDER BY name"
+    )?;
+    let peers = query.query_map(params![], |row| {
+        Ok(Peer {
+            name: row.get("name")?,
+            blobs: row.get::<&str, i64>("blobs")? as usize,
+            unacknowledged: row.get::<&str, i64>("unacknowledged")? as usize,
+        })
+    })?.collect::<Result<Vec<Peer>, rusqlite::Error>>()?;
+    Ok(peers)
+}
+
+// Forgets everything about the peer, so that the next bundle exported for it has everything. For
+// when the peer's repository has been lost or recreated.
+pub fn forget_peer(db: &Connection, peer: &str) -> Result<(), CommonplaceError> {
+    db.execute("DELETE FROM Peers WHERE name = ?1", params![peer])?;
+    Ok(())
+}
+
+#[derive(Clone)]
+struct NoteHead {
+    hash: Vec<u8>,
+    name: String,
+    mimetype: String,
+    modified: Timestamp,
+}
+
+fn note_head_from_row(row: &rusqlite::Row) -> Result<NoteHead, rusqlite::Error> {
+    Ok(NoteHead {
+        hash: row.get("hash")?,
+        name: row.get("name")?,
+        mimetype: row.get("mimetype")?,
+        modified: timestamp_from_row(row)?,
+    })
+}
+
+fn import_bundle_internal(db: &Connection) -> Result<ImportReport, CommonplaceError> {
+    let blobs = db.execute("INSERT OR IGNORE INTO main.Blobs (hash, contents) SELECT hash, contents FROM bundle.Blobs", params![])?;
+    let mut report = ImportReport { blobs, ..ImportReport::default() };
+
+    // Whatever is changed after this is newer than everything in the bundle.
+    let latest: Option<i64> = db.query_row(
+        "SELECT MAX(hlc) FROM (
+             SELECT hlc FROM bundle.Notes UNION ALL SELECT hlc FROM bundle.NoteHistory
+             UNION ALL SELECT hlc FROM bundle.Tags UNION ALL SELECT hlc FROM bundle.TagMap
+             UNION ALL SELECT hlc FROM bundle.Tombstones UNION ALL SELECT hlc FROM bundle.TagTombstones
+             UNION ALL SELECT hlc FROM bundle.TagMapTombstones UNION ALL SELECT hlc FROM bundle.LinkTombstones
+         )",
+        params![],
+        |row| row.get(0)
+    )?;
+    if let Some(latest) = latest {
+        observe(db, latest)?;
+    }
+
+    db.execute(
+        "INSERT OR IGNORE INTO main.Tombstones (id, time, hlc, device) SELECT id, time, hlc, device FROM bundle.Tombstones",
+        params![]
+    )?;
+    let deleted = {
+        let mut query = db.prepare("SELECT id FROM main.Notes WHERE id IN (SELECT id FROM main.Tombstones)")?;
+        let deleted = query.query_map(params![], |row| row.get(0))?.collect::<Result<Vec<Uuid>, rusqlite::Error>>()?;
+        deleted
+    };
+    for note in &deleted {
+        report.changes.push(Operation::DeleteNote { note: get_note(db, *note)? });
+        delete_note_internal(db, *note)?;
+    }
+    report.notes_deleted = deleted.len();
+
+    let mut changed = vec![];
+    let remote_notes = {
+        let mut query = db.prepare("SELECT * FROM bundle.Notes WHERE id NOT IN (SELECT id FROM main.Tombstones)")?;
+        let notes = query.query_map(params![], |row| Ok((row.get("id")?, note_head_from_row(row)?)))?
+            .collect::<Result<Vec<(Uuid, NoteHead)>, rusqlite::Error>>()?;
+        notes
+    };
+    for (id, remote) in remote_notes {
+        let local = db.query_row("SELECT * FROM main.Notes WHERE id = ?1", params![id], note_head_from_row).optional()?;
+        let local = match local {
+            Some(local) => local,
+            None => {
+                db.execute(
+                    "INSERT INTO main.Notes (id, hash, name, mimetype, hlc, device) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![id, remote.hash, remote.name, remote.mimetype, remote.modified.hlc, remote.modified.device]
+                )?;
+                report.notes_added += 1;
+                report.changes.push(Operation::AddNote { note: get_note(db, id)? });
+                changed.push(id);
+                continue;
+            },
+        };
+        if (&local.hash, &local.name, &local.mimetype) == (&remote.hash, &remote.name, &remote.mimetype) {
+            // The same head, modified since: a conflict that we recorded before then was resolved
+            // on the other side by keeping the version we already have.
+            if remote.modified > local.modified {
+                if db.execute("DELETE FROM main.Conflicts WHERE note_id = ?1", params![id])? > 0 {
+                    let hash = hash_from_bytes(&local.hash)?;
+                    report.changes.push(Operation::ResolveConflict { note: NoteId { uuid: id }, from: hash, to: hash });
+                }
+                db.execute(
+                    "UPDATE main.Notes SET hlc = ?1, device = ?2 WHERE id = ?3",
+                    params![remote.modified.hlc, remote.modified.device, id]
+                )?;
+            }
+            continue;
+        }
+
+        if merge_note(db, id, &local, &remote, &mut report)? {
+            changed.push(id);
+        }
+    }
+
+    report.history += db.execute(
+        "INSERT INTO main.NoteHistory (note_id, hash, name, mimetype, time, hlc, device)
+         SELECT note_id, hash, name, mimetype, time, hlc, device FROM bundle.NoteHistory AS b
+         WHERE note_id NOT IN (SELECT id FROM main.Tombstones)
+         AND NOT EXISTS (
+             SELECT 1 FROM main.NoteHistory AS h
+             WHERE h.note_id = b.note_id AND h.hash = b.hash AND h.name = b.name AND h.mimetype = b.mimetype AND h.time = b.time
+         )",
+        params![]
+    )?;
+
+    let tag_ids = import_tags(db, &mut report)?;
+    let remote_rows = |sql: &str| -> Result<Vec<(Uuid, Uuid)>, CommonplaceError> {
+        let mut query = db.prepare(sql)?;
+        let rows = query.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(Uuid, Uuid)>, rusqlite::Error>>()?;
+        Ok(rows)
+    };
+    import_tag_map(db, &tag_ids, &mut report)?;
+    for (tag, _) in remote_rows("SELECT tag_id, tag_id FROM bundle.HiddenTags")? {
+        if let Some(tag) = tag_ids.get(&tag) {
+            if db.execute("INSERT OR IGNORE INTO main.HiddenTags (tag_id) VALUES (?1)", params![tag])? > 0 {
+                report.changes.push(Operation::SetTagHidden { tag: TagId { uuid: *tag }, hidden: true });
+            }
+        }
+    }
+
+    db.execute(
+        "INSERT OR IGNORE INTO main.LinkTombstones (id, hlc, device) SELECT id, hlc, device FROM bundle.LinkTombstones",
+        params![]
+    )?;
+    let links = |sql: &str| -> Result<Vec<Link>, CommonplaceError> {
+        let mut query = db.prepare(sql)?;
+        let links = query.query_map(params![], link_from_row)?.collect::<Result<Vec<Link>, rusqlite::Error>>()?;
+        Ok(links)
+    };
+    for link in links("SELECT * FROM main.Links WHERE id IN (SELECT id FROM main.LinkTombstones) ORDER BY rowid")? {
+        db.execute("DELETE FROM main.Links WHERE id = ?1", params![link.id.uuid])?;
+        report.changes.push(Operation::DeleteLink { link });
+    }
+    let added = links(
+        "SELECT * FROM bundle.Links
+         WHERE id NOT IN (SELECT id FROM main.LinkTombstones)
+         AND id NOT IN (SELECT id FROM main.Links)
+         AND from_note IN (SELECT id FROM main.Notes)
+         AND (to_note IS NULL OR to_note IN (SELECT id FROM main.Notes))
+         ORDER BY rowid"
+    )?;
+    for link in added {
+        db.execute(
+            "INSERT INTO main.Links (id, from_note, from_anchor, to_note, to_url, to_anchor, kind)
+             SELECT id, from_note, from_anchor, to_note, to_url, to_anchor, kind FROM bundle.Links WHERE id = ?1",
+            params![link.id.uuid]
+        )?;
+        report.changes.push(Operation::AddLink { link });
+    }
+
+    for note in changed {
+        index_note_contents(db, note)?;
+        sync_cards(db, note)?;
+    }
+
+    Ok(report)
+}
+
+fn note_hashes(db: &Connection, sql: &str, note: Uuid) -> Result<Vec<Vec<u8>>, CommonplaceError> {
+    let mut query = db.prepare(sql)?;
+    let hashes = query.query_map(params![note], |row| row.get(0))?.collect::<Result<Vec<Vec<u8>>, rusqlite::Error>>()?;
+    Ok(hashes)
+}
+
+fn blob_text(db: &Connection, hash: &[u8]) -> Result<String, CommonplaceError> {
+    let contents: Vec<u8> = db.query_row("SELECT contents FROM main.Blobs WHERE hash = ?1", params![hash], |row| row.get(0))?;
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+// Replaces the current version of the note with the head. The note_update_history trigger moves
+// the current version into the history.
+fn set_head(db: &Connection, note: Uuid, current: &NoteHead, head: &NoteHead, report: &mut ImportReport) -> Result<(), CommonplaceError> {
+    db.execute(
+        "UPDATE main.Notes SET hash = ?1, name = ?2, mimetype = ?3, hlc = ?4, device = ?5 WHERE id = ?6",
+        params![head.hash, head.name, head.mimetype, head.modified.hlc, head.modified.device, note]
+    )?;
+    if current.hash != head.hash {
+        let (from, to) = (hash_from_bytes(&current.hash)?, hash_from_bytes(&head.hash)?);
+        report.changes.push(Operation::UpdateNote { note: NoteId { uuid: note }, from, to });
+    }
+    if current.name != head.name {
+        report.changes.push(Operation::RenameNote { note: NoteId { uuid: note }, from: current.name.clone(), to: head.name.clone() });
+    }
+    Ok(())
+}
+
+fn add_to_history(db: &Connection, note: Uuid, head: &NoteHead, report: &mut ImportReport) -> Result<(), CommonplaceError> {
+    report.history += db.execute(
+        "INSERT INTO main.NoteHistory (note_id, hash, name, mimetype, time, hlc, device)
+         SELECT ?1, ?2, ?3, ?4, strftime('%s', 'now'), ?5, ?6
+         WHERE NOT EXISTS (
+             SELECT 1 FROM main.NoteHistory WHERE note_id = ?1 AND hash = ?2 AND name = ?3 AND mimetype = ?4
+         )",
+        params![note, head.hash, head.name, head.mimetype, head.modified.hlc, head.modified.device]
+    )?;
+    Ok(())
+}
+
+// Decides what the note should be when it's different in the bundle, returning whether it changed.
+fn merge_note(db: &Connection, note: Uuid, local: &NoteHead, remote: &NoteHead, report: &mut ImportReport) -> Result<bool, CommonplaceError> {
+    let local_history = note_hashes(db, "SELECT hash FROM main.NoteHistory WHERE note_id = ?1", note)?;
+    let remote_history = note_hashes(db, "SELECT hash FROM bundle.Lineage WHERE note_id = ?1 ORDER BY time DESC, id DESC", note)?;
+    let local_has_remote = local_history.contains(&remote.hash);
+    let remote_has_local = remote_history.contains(&local.hash);
+
+    // Timestamps only tie if both heads came from the same device id, which happens when a
+    // repository was copied, so then ties are broken by hash. Either way both sides pick the same
+    // winner, and merging the older head into the newer one makes them come up with the same merge.
+    let remote_is_newer = (remote.modified, &remote.hash) > (local.modified, &local.hash);
+    let (older, newer) = if remote_is_newer { (local, remote) } else { (remote, local) };
+
+    // One side has had the other side's head, and changed it since. Both sides have had each
+    // other's when a conflict was resolved in favour of the head that lost, so then the newer one
+    // wins.
+    if local.hash != remote.hash && (local_has_remote || remote_has_local) {
+        if !remote_has_local || (local_has_remote && !remote_is_newer) {
+            add_to_history(db, note, remote, report)?;
+            return Ok(false);
+        }
+        // Both sides recorded the same conflict, if there was one, and the other side has moved on
+        // from it.
+        if db.execute("DELETE FROM main.Conflicts WHERE note_id = ?1", params![note])? > 0 {
+            let (from, to) = (hash_from_bytes(&local.hash)?, hash_from_bytes(&remote.hash)?);
+            report.changes.push(Operation::ResolveConflict { note: NoteId { uuid: note }, from, to });
+        }
+        set_head(db, note, local, remote, report)?;
+        report.notes_updated += 1;
+        return Ok(true);
+    }
+
+    let base = if local.hash != remote.hash && local.mimetype.starts_with("text/") && remote.mimetype.starts_with("text/") {
+        remote_history.iter().find(|x| local_history.contains(x))
+    } else {
+        None
+    };
+
+    if let Some(base) = base {
+        if let Some(merged) = merge_lines(&blob_text(db, base)?, &blob_text(db, &older.hash)?, &blob_text(db, &newer.hash)?) {
+            let hash = add_bytes_to_blobstore(db, merged.into_bytes())?.as_bytes().to_vec();
+            set_head(db, note, local, &NoteHead { hash, ..newer.clone() }, report)?;
+            add_to_history(db, note, remote, report)?;
+            report.notes_merged += 1;
+            return Ok(true);
+        }
+        db.execute(
+            "INSERT OR REPLACE INTO main.Conflicts (note_id, base, ours, theirs, time) VALUES (?1, ?2, ?3, ?4, strftime('%s', 'now'))",
+            params![note, base, local.hash, remote.hash]
+        )?;
+        report.conflicts += 1;
+        report.changes.push(Operation::RecordConflict {
+            note: NoteId { uuid: note },
+            base: hash_from_bytes(base)?,
+            ours: hash_from_bytes(&local.hash)?,
+            theirs: hash_from_bytes(&remote.hash)?,
+        });
+    }
+
+    if remote_is_newer {
+        set_head(db, note, local, remote, report)?;
+        report.notes_updated += 1;
+    } else {
+        add_to_history(db, note, remote, report)?;
+    }
+    Ok(remote_is_newer)
+}
+
+// Deletes the tags that were deleted in the bundle, and adds the bundle's other tags, parents
+// first, returning a map from the bundle's tag ids to the local ones. Like notes, deleted tags stay
+// deleted. A tag that was renamed or moved more recently in the bundle than here is renamed or
+// moved here too, unless the name is taken or the move would make a cycle.
+fn import_tags(db: &Connection, report: &mut ImportReport) -> Result<HashMap<Uuid, Uuid>, CommonplaceError> {
+    db.execute(
+        "INSERT OR IGNORE INTO main.TagTombstones (id, hlc, device) SELECT id, hlc, device FROM bundle.TagTombstones",
+        params![]
+    )?;
+    let deleted = {
+        let mut query = db.prepare(
+            "SELECT TagTombstones.* FROM main.TagTombstones JOIN main.Tags ON Tags.id = TagTombstones.id"
+        )?;
+        let deleted = query.query_map(params![], |row| Ok((row.get("id")?, timestamp_from_row(row)?)))?
+            .collect::<Result<Vec<(Uuid, Timestamp)>, rusqlite::Error>>()?;
+        deleted
+    };
+    for (tag, deleted) in deleted {
+        let row = match get_tag_row(db, tag) {
+            Ok(row) => row,
+            // Already deleted along with one of its ancestors.
+            Err(CommonplaceError::Sqlite(rusqlite::Error::QueryReturnedNoRows)) => continue,
+            Err(e) => return Err(e),
+        };
+        let untagged = delete_tag_internal(db, tag, &deleted)?;
+        report.changes.push(Operation::DeleteTag { tag: row, untagged });
+    }
+
+    let mut pending = {
+        let mut query = db.prepare("SELECT * FROM bundle.Tags WHERE id NOT IN (SELECT id FROM main.TagTombstones)")?;
+        let tags = query.query_map(params![], |row| Ok((row.get("id")?, row.get("name")?, row.get("parent")?, timestamp_from_row(row)?)))?
+            .collect::<Result<Vec<(Uuid, String, Option<Uuid>, Timestamp)>, rusqlite::Error>>()?;
+        tags
+    };
+
+    let mut tag_ids = HashMap::new();
+    loop {
+        let mut waiting = vec![];
+        let before = pending.len();
+        for (id, name, parent, modified) in pending {
+            let parent = match parent {
+                Some(parent) => match tag_ids.get(&parent) {
+                    Some(parent) => Some(*parent),
+                    None => {
+                        waiting.push((id, name, Some(parent), modified));
+                        continue;
+                    },
+                },
+                None => None,
+            };
+
+            let existing = db.query_row(
+                "SELECT name, parent, hlc, device FROM main.Tags WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get("name")?, row.get("parent")?, timestamp_from_row(row)?))
+            ).optional()?;
+            let local = if let Some((local_name, local_parent, local_modified)) = existing {
+                let changed: bool = (&name, parent) != (&local_name, local_parent);
+                if changed && modified > local_modified && !has_ancestor(db, parent, id)? {
+                    match check_tag_name_free(db, id, &name, parent) {
+                        Ok(()) => {
+                            db.execute(
+                                "UPDATE main.Tags SET name = ?1, parent = ?2, hlc = ?3, device = ?4 WHERE id = ?5",
+                                params![name, parent, modified.hlc, modified.device, id]
+                            )?;
+                            let tag = TagId { uuid: id };
+                            if name != local_name {
+                                report.changes.push(Operation::RenameTag { tag, from: local_name, to: name.clone() });
+                            }
+                            if parent != local_parent {
+                                let (from, to) = (local_parent.map(|uuid| TagId { uuid }), parent.map(|uuid| TagId { uuid }));
+                                report.changes.push(Operation::MoveTag { tag, from, to });
+                            }
+                        },
+                        Err(CommonplaceError::TagNameTaken) => {},
+                        Err(e) => return Err(e),
+                    }
+                }
+                id
+            } else {
+                let same_name: Option<Uuid> = db.query_row(
+                    "SELECT id FROM main.Tags WHERE name = ?1 AND parent IS ?2",
+                    params![name, parent],
+                    |row| row.get(0)
+                ).optional()?;
+                match same_name {
+                    Some(local) => local,
+                    None => {
+                        db.execute(
+                            "INSERT INTO main.Tags (id, name, parent, hlc, device) VALUES (?1, ?2, ?3, ?4, ?5)",
+                            params![id, name, parent, modified.hlc, modified.device]
+                        )?;
+                        report.tags += 1;
+                        report.changes.push(Operation::CreateTag { tag: get_tag_row(db, id)? });
+                        id
+                    },
+                }
+            };
+            tag_ids.insert(id, local);
+        }
+        // Whatever's left has a parent that isn't in the bundle, or is part of a cycle.
+        if waiting.is_empty() || waiting.len() == before {
+            break;
+        }
+        pending = waiting;
+    }
+    Ok(tag_ids)
+}
+
+// Tagging and untagging are last-write-wins for each (note, tag) pair: whichever of TagMap and
+// TagMapTombstones has the newer timestamp, on either side, is kept.
+fn import_tag_map(db: &Connection, tag_ids: &HashMap<Uuid, Uuid>, report: &mut ImportReport) -> Result<(), CommonplaceError> {
+    let remote_rows = |table: &str| -> Result<Vec<(Uuid, Uuid, Timestamp)>, CommonplaceError> {
+        let mut query = db.prepare(&format!("SELECT * FROM bundle.{} WHERE note_id IN (SELECT id FROM main.Notes)", table))?;
+        let rows = query.query_map(params![], |row| Ok((row.get("note_id")?, row.get("tag_id")?, timestamp_from_row(row)?)))?
+            .collect::<Result<Vec<(Uuid, Uuid, Timestamp)>, rusqlite::Error>>()?;
+        Ok(rows)
+    };
+    let local_timestamp = |table: &str, note: Uuid, tag: Uuid| -> Result<Option<Timestamp>, CommonplaceError> {
+        Ok(db.query_row(
+            &format!("SELECT hlc, device FROM main.{} WHERE note_id = ?1 AND tag_id = ?2", table),
+            params![note, tag],
+            timestamp_from_row
+        ).optional()?)
+    };
+
+    for (table, other) in &[("TagMap", "TagMapTombstones"), ("TagMapTombstones", "TagMap")] {
+        for (note, tag, remote) in remote_rows(table)? {
+            let tag = match tag_ids.get(&tag) {
+                Some(tag) => *tag,
+                None => continue,
+            };
+            if local_timestamp(other, note, tag)? > Some(remote) {
+                continue;
+            }
+            let local = local_timestamp(table, note, tag)?;
+            if local < Some(remote) {
+                let removed = db.execute(&format!("DELETE FROM main.{} WHERE note_id = ?1 AND tag_id = ?2", other), params![note, tag])?;
+                db.execute(
+                    &format!("INSERT OR REPLACE INTO main.{} (note_id, tag_id, hlc, device) VALUES (?1, ?2, ?3, ?4)", table),
+                    params![note, tag, remote.hlc, remote.device]
+                )?;
+                let (note, tag) = (NoteId { uuid: note }, TagId { uuid: tag });
+                if *table == "TagMap" && local.is_none() {
+                    report.changes.push(Operation::TagNote { note, tag });
+                } else if *table == "TagMapTombstones" && removed > 0 {
+                    report.changes.push(Operation::UntagNote { note, tag });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+    use crate::{init_memex, add_note, update_note_bytes, delete_note, get_all_notes, get_note_contents, get_note_history, get_conflict, list_conflicts};
+
+    fn repo() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        init_memex(&db).unwrap();
+        db
+    }
+
+    fn temp_path(extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("commonplace-test-{}.{}", Uuid::new_v4(), extension))
+    }
+
+    fn export(db: &Connection, peer: Option<&str>) -> PathBuf {
+        let path = temp_path("bundle");
+        export_bundle(db, &path, peer).unwrap();
+        path
+    }
+
+    fn sync(from: &Connection, to: &Connection) -> Result<ImportReport, CommonplaceError> {
+        let path = export(from, None);
+        let report = import_bundle(to, &path, None);
+        std::fs::remove_file(&path).unwrap();
+        report
+    }
+
+    // Adds a note to a and syncs it to b. The extension decides the note's mimetype, and so whether
+    // it can be merged.
+    fn shared_note(a: &Connection, b: &Connection, extension: &str, contents: &str) -> Uuid {
+        let path = temp_path(extension);
+        std::fs::write(&path, contents).unwrap();
+        let note = add_note(a, format!("note.{}", extension), path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        sync(a, b).unwrap();
+        note
+    }
+
+    // Makes sure that the next change is timestamped after the last one, on any repository.
+    fn wait() {
+        thread::sleep(Duration::from_millis(2));
+    }
+
+    fn hash(contents: &str) -> [u8; 32] {
+        *blake3::hash(contents.as_bytes()).as_bytes()
+    }
+
+    fn in_history(db: &Connection, note: Uuid, contents: &str) -> bool {
+        get_note_history(db, note).unwrap().iter().any(|x| x.hash == hash(contents))
+    }
+
+    #[test]
+    fn the_newer_head_wins_and_the_other_is_kept() {
+        let (a, b) = (repo(), repo());
+        let note = shared_note(&a, &b, "bin", "base");
+        update_note_bytes(&a, note, b"older".to_vec()).unwrap();
+        wait();
+        update_note_bytes(&b, note, b"newer".to_vec()).unwrap();
+
+        let report = sync(&b, &a).unwrap();
+        assert_eq!(report.notes_updated, 1);
+        assert_eq!(get_note_contents(&a, note).unwrap(), b"newer");
+        assert!(in_history(&a, note, "older"));
+
+        let report = sync(&a, &b).unwrap();
+        assert_eq!(report.notes_updated, 0);
+        assert_eq!(get_note_contents(&b, note).unwrap(), b"newer");
+        assert!(in_history(&b, note, "older"));
+    }
+
+    #[test]
+    fn deletions_beat_later_edits() {
+        let (a, b) = (repo(), repo());
+        let note = shared_note(&a, &b, "md", "base\n");
+        delete_note(&a, note).unwrap();
+        wait();
+        update_note_bytes(&b, note, b"edited\n".to_vec()).unwrap();
+
+        let report = sync(&b, &a).unwrap();
+        assert_eq!(report.notes_added, 0);
+        assert!(matches!(get_note(&a, note), Err(CommonplaceError::Sqlite(rusqlite::Error::QueryReturnedNoRows))));
+
+        let report = sync(&a, &b).unwrap();
+        assert_eq!(report.notes_deleted, 1);
+        assert!(matches!(get_note(&b, note), Err(CommonplaceError::Sqlite(rusqlite::Error::QueryReturnedNoRows))));
+    }
+
+    #[test]
+    fn incremental_bundles_need_the_blobs_they_leave_out() {
+        let (a, b) = (repo(), repo());
+        shared_note(&a, &b, "md", "contents\n");
+
+        // Once b acknowledges a bundle from a, a's bundles for b leave out the note's blob.
+        let path = export(&a, Some("b"));
+        import_bundle(&b, &path, Some("a")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let path = export(&b, Some("a"));
+        import_bundle(&a, &path, Some("b")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let path = export(&a, Some("b"));
+
+        let c = repo();
+        assert!(matches!(import_bundle(&c, &path, None), Err(CommonplaceError::IncompleteBundle(1))));
+        assert!(get_all_notes(&c).unwrap().is_empty());
+        import_bundle(&b, &path, Some("a")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changes_to_different_lines_are_merged() {
+        let (a, b) = (repo(), repo());
+        let note = shared_note(&a, &b, "md", "one\ntwo\nthree\nfour\nfive\n");
+        update_note_bytes(&a, note, b"ONE\ntwo\nthree\nfour\nfive\n".to_vec()).unwrap();
+        wait();
+        update_note_bytes(&b, note, b"one\ntwo\nthree\nfour\nFIVE\n".to_vec()).unwrap();
+
+        let report = sync(&b, &a).unwrap();
+        assert_eq!((report.notes_merged, report.conflicts), (1, 0));
+        assert_eq!(get_note_contents(&a, note).unwrap(), b"ONE\ntwo\nthree\nfour\nFIVE\n");
+        assert!(list_conflicts(&a).unwrap().is_empty());
+
+        sync(&a, &b).unwrap();
+        assert_eq!(get_note_contents(&b, note).unwrap(), b"ONE\ntwo\nthree\nfour\nFIVE\n");
+    }
+
+    #[test]
+    fn overlapping_changes_are_recorded_as_conflicts() {
+        let (a, b) = (repo(), repo());
+        let note = shared_note(&a, &b, "md", "one\ntwo\nthree\n");
+        update_note_bytes(&a, note, b"one\nTWO\nthree\n".to_vec()).unwrap();
+        wait();
+        update_note_bytes(&b, note, b"one\n2\nthree\n".to_vec()).unwrap();
+
+        let report = sync(&b, &a).unwrap();
+        assert_eq!((report.notes_merged, report.conflicts), (0, 1));
+        let conflict = get_conflict(&a, note).unwrap();
+        assert_eq!(conflict.base, hash("one\ntwo\nthree\n"));
+        assert_eq!(conflict.ours, hash("one\nTWO\nthree\n"));
+        assert_eq!(conflict.theirs, hash("one\n2\nthree\n"));
+        assert_eq!(get_note_contents(&a, note).unwrap(), b"one\n2\nthree\n");
+        assert!(in_history(&a, note, "one\nTWO\nthree\n"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt;
+use std::str::FromStr;
+use std::convert::TryFrom;
+use serde::{Serialize, Deserialize};
+
+// An anchor is a place inside of a note that a link can point at or be attached to. They're
+// written as strings, which is also how they're stored and sent over the wire:
+//
+//   text:120-250                  bytes 120 to 250 of a text note
+//   pdf:3                         page 3 (counting from 1) of a PDF
+//   pdf:3@72,100,200,50           a box on page 3, in PDF points from the top left of the page
+//   image:10,20,300,200           a box in an image, in pixels from the top left
+//   media:65.5-90                 seconds 65.5 to 90 of a video or audio file
+//   media:65.5-90@10,20,300,200   a box in a video during that time, in pixels
+//
+// Boxes are x,y,width,height. These strings are meant to be stable, since they end up in
+// repositories, so don't change the format of an existing kind — add a new one instead.
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Anchor {
+    Text { start: u64, end: u64 },
+    Pdf { page: u32, rect: Option<Rect> },
+    Image { rect: Rect },
+    Media { start: f64, end: f64, rect: Option<Rect> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnchorError {
+    pub message: String,
+}
+
+impl fmt::Display for AnchorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid anchor: {}", self.message)
+    }
+}
+
+impl std::error::Error for AnchorError {}
+
+fn anchor_error<T>(message: String) -> Result<T, AnchorError> {
+    Err(AnchorError { message })
+}
+
+fn parse_number<T: FromStr>(s: &str) -> Result<T, AnchorError> {
+    match s.parse() {
+        Ok(x) => Ok(x),
+        Err(_) => anchor_error(format!("{:?} is not a number", s)),
+    }
+}
+
+fn parse_seconds(s: &str) -> Result<f64, AnchorError> {
+    let x: f64 = parse_number(s)?;
+    if !x.is_finite() || x < 0.0 {
+        return anchor_error(format!("{:?} is not a time", s));
+    }
+    Ok(x)
+}
+
+impl fmt::Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+impl FromStr for Rect {
+    type Err = AnchorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<f64> = s.split(',').map(parse_number).collect::<Result<_, _>>()?;
+        match parts[..] {
+            [x, y, width, height] if parts.iter().all(|x| x.is_finite()) && width >= 0.0 && height >= 0.0 => {
+                Ok(Rect { x, y, width, height })
+            },
+            _ => anchor_error(format!("{:?} is not a box, expected x,y,width,height", s)),
+        }
+    }
+}
+
+impl fmt::Display for Anchor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anchor::Text { start, end } => write!(f, "text:{}-{}", start, end),
+            Anchor::Pdf { page, rect: None } => write!(f, "pdf:{}", page),
+            Anchor::Pdf { page, rect: Some(rect) } => write!(f, "pdf:{}@{}", page, rect),
+            Anchor::Image { rect } => write!(f, "image:{}", rect),
+            Anchor::Media { start, end, rect: None } => write!(f, "media:{}-{}", start, end),
+            Anchor::Media { start, end, rect: Some(rect) } => write!(f, "media:{}-{}@{}", start, end, rect),
+        }
+    }
+}
+
+impl FromStr for Anchor {
+    type Err = AnchorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return anchor_error(format!("{:?} has no kind", s)),
+        };
+        let (range, rect) = match rest.find('@') {
+            Some(i) => (&rest[..i], Some(rest[i + 1..].parse::<Rect>()?)),
+            None => (rest, None),
+        };
+        let split_range = || match range.find('-') {
+            Some(i) => Ok((&range[..i], &range[i + 1..])),
+            None => anchor_error(format!("{:?} is not a range, expected start-end", range)),
+        };
+
+        let anchor = match kind {
+            "text" => {
+                if rect.is_some() {
+                    return anchor_error("text anchors can't have a box".to_string());
+                }
+                let (start, end) = split_range()?;
+                Anchor::Text { start: parse_number(start)?, end: parse_number(end)? }
+            },
+            "pdf" => {
+                let page = parse_number(range)?;
+                if page == 0 {
+                    return anchor_error("pdf pages start at 1".to_string());
+                }
+                Anchor::Pdf { page, rect }
+            },
+            "image" => Anchor::Image { rect: rest.parse()? },
+            "media" => {
+                let (start, end) = split_range()?;
+                Anchor::Media { start: parse_seconds(start)?, end: parse_seconds(end)?, rect }
+            },
+            _ => return anchor_error(format!("{:?} is not a kind of anchor", kind)),
+        };
+
+        match anchor {
+            Anchor::Text { start, end } if start > end => anchor_error(format!("{:?} ends before it starts", s)),
+            Anchor::Media { start, end, .. } if start > end => anchor_error(format!("{:?} ends before it starts", s)),
+            anchor => Ok(anchor),
+        }
+    }
+}
+
+impl From<Anchor> for String {
+    fn from(anchor: Anchor) -> Self {
+        anchor.to_string()
+    }
+}
+
+impl TryFrom<String> for Anchor {
+    type Error = AnchorError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Anchor {
+    // Whether this kind of anchor makes sense in a note with this mimetype.
+    pub fn applies_to(&self, mimetype: &str) -> bool {
+        match self {
+            Anchor::Text { .. } => mimetype.starts_with("text/"),
+            Anchor::Pdf { .. } => mimetype == "application/pdf",
+            Anchor::Image { .. } => mimetype.starts_with("image/"),
+            Anchor::Media { rect: None, .. } => mimetype.starts_with("video/") || mimetype.starts_with("audio/"),
+            Anchor::Media { rect: Some(_), .. } => mimetype.starts_with("video/"),
+        }
+    }
+
+    // The URL fragment (without the #) that browsers use to open a document at this anchor — PDF
+    // open parameters for PDFs, and media fragments for everything else. Browsers have no way to
+    // point at a byte range of text, so text anchors have to be handled by whatever displays them.
+    pub fn fragment(&self) -> Option<String> {
+        match self {
+            Anchor::Text { .. } => None,
+            Anchor::Pdf { page, rect: None } => Some(format!("page={}", page)),
+            Anchor::Pdf { page, rect: Some(rect) } => Some(format!("page={}&viewrect={}", page, rect)),
+            Anchor::Image { rect } => Some(format!("xywh={}", rect)),
+            Anchor::Media { start, end, rect: None } => Some(format!("t={},{}", start, end)),
+            Anchor::Media { start, end, rect: Some(rect) } => Some(format!("t={},{}&xywh={}", start, end, rect)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOX: Rect = Rect { x: 10.0, y: 20.0, width: 300.0, height: 200.0 };
+
+    #[test]
+    fn parse() {
+        assert_eq!("text:120-250".parse(), Ok(Anchor::Text { start: 120, end: 250 }));
+        assert_eq!("pdf:3".parse(), Ok(Anchor::Pdf { page: 3, rect: None }));
+        assert_eq!("pdf:3@10,20,300,200".parse(), Ok(Anchor::Pdf { page: 3, rect: Some(BOX) }));
+        assert_eq!("image:10,20,300,200".parse(), Ok(Anchor::Image { rect: BOX }));
+        assert_eq!("media:65.5-90".parse(), Ok(Anchor::Media { start: 65.5, end: 90.0, rect: None }));
+        assert_eq!("media:65.5-90@10,20,300,200".parse(), Ok(Anchor::Media { start: 65.5, end: 90.0, rect: Some(BOX) }));
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in &[
+            "text:0-0",
+            "text:120-250",
+            "pdf:3",
+            "pdf:3@72,100.25,200,50",
+            "image:10,20,300,200",
+            "image:-5,-5,0,0",
+            "media:65.5-90",
+            "media:0-0.125@10,20,300,200",
+        ] {
+            let anchor: Anchor = s.parse().unwrap();
+            assert_eq!(&anchor.to_string(), s);
+            let json = serde_json::to_string(&anchor).unwrap();
+            assert_eq!(json, format!("\"{}\"", s));
+            assert_eq!(serde_json::from_str::<Anchor>(&json).unwrap(), anchor);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for s in &[
+            "",
+            "120-250",
+            "video:1-2",
+            "text:250-120",
+            "text:-1-5",
+            "text:1-2@10,20,300,200",
+            "text:12",
+            "pdf:0",
+            "pdf:one",
+            "pdf:3@10,20,300",
+            "image:10,20,-300,200",
+            "image:10,20,inf,200",
+            "media:90-65.5",
+            "media:-1-5",
+            "media:NaN-5",
+        ] {
+            assert!(s.parse::<Anchor>().is_err(), "{:?} should be an error", s);
+        }
+        assert!(serde_json::from_str::<Anchor>("\"pdf:0\"").is_err());
+    }
+
+    #[test]
+    fn fragments() {
+        assert_eq!(Anchor::Text { start: 1, end: 2 }.fragment(), None);
+        assert_eq!("pdf:3".parse::<Anchor>().unwrap().fragment().as_deref(), Some("page=3"));
+        assert_eq!("pdf:3@10,20,300,200".parse::<Anchor>().unwrap().fragment().as_deref(), Some("page=3&viewrect=10,20,300,200"));
+        assert_eq!("media:65.5-90".parse::<Anchor>().unwrap().fragment().as_deref(), Some("t=65.5,90"));
+    }
+}
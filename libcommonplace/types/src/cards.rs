@@ -0,0 +1,303 @@
+use std::fmt;
+use uuid::Uuid;
+use serde::{Serialize, Deserialize};
+use crate::{NoteId, DiffOp, diff_ops};
+
+// Cards are written inside of text notes, in one of two ways. A question and answer:
+//
+//   Q: What does SM-2 stand for?
+//   A: SuperMemo 2
+//
+// where each side can go over several lines, and ends at the next Q: or A: or at a blank line. Or
+// a cloze deletion:
+//
+//   The ease factor in SM-2 never goes below {{1.3}}.
+//
+// which makes a card out of the paragraph it's in, with the deletion hidden. A paragraph with
+// several deletions makes one card for each of them.
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct CardId {
+    pub uuid: Uuid,
+}
+
+impl fmt::Display for CardId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.uuid)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CardText {
+    pub front: String,
+    pub back: String,
+}
+
+const CLOZE_HIDDEN: &str = "[...]";
+
+fn extract_question(paragraph: &[&str], cards: &mut Vec<CardText>) {
+    let mut question: Option<Vec<&str>> = None;
+    let mut answer: Option<Vec<&str>> = None;
+    let mut finish = |question: &mut Option<Vec<&str>>, answer: &mut Option<Vec<&str>>| {
+        if let (Some(q), Some(a)) = (question.take(), answer.take()) {
+            cards.push(CardText { front: q.join("\n").trim().to_string(), back: a.join("\n").trim().to_string() });
+        }
+    };
+
+    for line in paragraph {
+        if let Some(rest) = line.strip_prefix("Q:") {
+            finish(&mut question, &mut answer);
+            question = Some(vec![rest]);
+        } else if let Some(rest) = line.strip_prefix("A:") {
+            if question.is_some() {
+                answer = Some(vec![rest]);
+            }
+        } else if let Some(answer) = answer.as_mut() {
+            answer.push(line);
+        } else if let Some(question) = question.as_mut() {
+            question.push(line);
+        }
+    }
+    finish(&mut question, &mut answer);
+}
+
+// The (start, end) byte ranges of each {{deletion}}, including the braces.
+fn cloze_ranges(paragraph: &str) -> Vec<(usize, usize)> {
+    let mut ranges = vec![];
+    let mut i = 0;
+    while let Some(start) = paragraph[i..].find("{{").map(|x| x + i) {
+        match paragraph[start + 2..].find("}}") {
+            Some(len) => {
+                ranges.push((start, start + 2 + len + 2));
+                i = start + 2 + len + 2;
+            },
+            None => break,
+        }
+    }
+    ranges
+}
+
+fn extract_clozes(paragraph: &str, cards: &mut Vec<CardText>) {
+    let ranges = cloze_ranges(paragraph);
+    for (hidden, &(start, end)) in ranges.iter().enumerate() {
+        let mut front = String::new();
+        let mut i = 0;
+        for (j, &(s, e)) in ranges.iter().enumerate() {
+            front.push_str(&paragraph[i..s]);
+            front.push_str(if j == hidden { CLOZE_HIDDEN } else { &paragraph[s + 2..e - 2] });
+            i = e;
+        }
+        front.push_str(&paragraph[i..]);
+        cards.push(CardText { front: front.trim().to_string(), back: paragraph[start + 2..end - 2].trim().to_string() });
+    }
+}
+
+pub fn extract_cards(text: &str) -> Vec<CardText> {
+    let mut cards = vec![];
+    let mut paragraph: Vec<&str> = vec![];
+    for line in text.lines().chain(std::iter::once("")) {
+        if !line.trim().is_empty() {
+            paragraph.push(line);
+            continue;
+        }
+        if paragraph.iter().any(|x| x.starts_with("Q:")) {
+            extract_question(&paragraph, &mut cards);
+        } else if !paragraph.is_empty() {
+            extract_clozes(&paragraph.join("\n"), &mut cards);
+        }
+        paragraph.clear();
+    }
+    cards
+}
+
+// How alike two cards are, from 0 to 1, going by how many of their words are the same and in the
+// same order.
+pub fn card_similarity(a: &CardText, b: &CardText) -> f64 {
+    let words = |card: &CardText| -> Vec<String> {
+        card.front.split_whitespace().map(|x| x.to_string())
+            .chain(std::iter::once("\n".to_string()))
+            .chain(card.back.split_whitespace().map(|x| x.to_string()))
+            .collect()
+    };
+    let (a, b) = (words(a), words(b));
+    let equal = diff_ops(&a, &b).iter().filter(|x| matches!(x, DiffOp::Equal(..))).count();
+    2.0 * equal as f64 / (a.len() + b.len()) as f64
+}
+
+// How well a card was remembered, from the buttons you get when reviewing it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Grade {
+    Again,
+    Hard,
+    Good,
+    Easy,
+}
+
+impl Grade {
+    // The 0-5 response quality that SM-2 is defined in terms of. Anything below 3 is a lapse.
+    pub fn quality(&self) -> u8 {
+        match self {
+            Grade::Again => 1,
+            Grade::Hard => 3,
+            Grade::Good => 4,
+            Grade::Easy => 5,
+        }
+    }
+}
+
+// SM-2 scheduling state. `interval` is in days.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub ease: f64,
+    pub interval: u32,
+    pub repetitions: u32,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule { ease: 2.5, interval: 0, repetitions: 0 }
+    }
+}
+
+impl Schedule {
+    pub fn next(&self, grade: Grade) -> Schedule {
+        let q = grade.quality() as f64;
+        let ease = (self.ease + 0.1 - (5.0 - q) * (0.08 + (5.0 - q) * 0.02)).max(1.3);
+        if grade.quality() < 3 {
+            return Schedule { ease, interval: 1, repetitions: 0 };
+        }
+        let interval = match self.repetitions {
+            0 => 1,
+            1 => 6,
+            _ => (self.interval as f64 * self.ease).round() as u32,
+        };
+        Schedule { ease, interval, repetitions: self.repetitions + 1 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Card {
+    pub id: CardId,
+    pub note_id: NoteId,
+    pub front: String,
+    pub back: String,
+    pub schedule: Schedule,
+    // UTC epoch time
+    pub due: i64,
+    // The card's text is no longer in its note. Orphaned cards aren't reviewed, but are kept around
+    // with their history in case the text comes back.
+    pub orphaned: bool,
+}
+
+impl Card {
+    pub fn text(&self) -> CardText {
+        CardText { front: self.front.clone(), back: self.back.clone() }
+    }
+}
+
+// A card whose text has changed since the last time it was reviewed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChangedCard {
+    pub card: Card,
+    pub reviewed: CardText,
+    // UTC epoch time
+    pub reviewed_time: i64,
+}
+
+// A row of the review log: what the card said when it was reviewed, and how it was scheduled
+// afterwards.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Review {
+    // UTC epoch time
+    pub time: i64,
+    // SM-2 quality, 0-5
+    pub quality: u8,
+    pub ease: f64,
+    pub interval: u32,
+    pub front: String,
+    pub back: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn card(front: &str, back: &str) -> CardText {
+        CardText { front: front.to_string(), back: back.to_string() }
+    }
+
+    fn assert_schedule(schedule: Schedule, ease: f64, interval: u32, repetitions: u32) {
+        assert!((schedule.ease - ease).abs() < 1e-9, "ease is {}, expected {}", schedule.ease, ease);
+        assert_eq!((schedule.interval, schedule.repetitions), (interval, repetitions));
+    }
+
+    #[test]
+    fn questions() {
+        let text = "Q: What does SM-2 stand for?\nA: SuperMemo 2\n\nQ: Which\nlines?\nA: All\nof them\nQ: Second?\nA: Yes\n";
+        assert_eq!(extract_cards(text), vec![
+            card("What does SM-2 stand for?", "SuperMemo 2"),
+            card("Which\nlines?", "All\nof them"),
+            card("Second?", "Yes"),
+        ]);
+    }
+
+    #[test]
+    fn questions_need_answers() {
+        assert_eq!(extract_cards("Q: Unanswered?\n\nA: Unasked\n"), vec![]);
+        assert_eq!(extract_cards("Q: Unanswered?\nQ: Answered?\nA: Yes\n"), vec![card("Answered?", "Yes")]);
+    }
+
+    #[test]
+    fn clozes() {
+        assert_eq!(extract_cards("Ease never goes below {{1.3}}.\n"), vec![card("Ease never goes below [...].", "1.3")]);
+        assert_eq!(extract_cards("The {{first}} and\nthe {{second}}.\n\nNot a card.\n"), vec![
+            card("The [...] and\nthe second.", "first"),
+            card("The first and\nthe [...].", "second"),
+        ]);
+    }
+
+    #[test]
+    fn unterminated_cloze() {
+        assert_eq!(extract_cards("An {{unterminated cloze.\n"), vec![]);
+        assert_eq!(extract_cards("A {{cloze}} and {{not\n"), vec![card("A [...] and {{not", "cloze")]);
+    }
+
+    #[test]
+    fn questions_take_precedence_over_clozes() {
+        assert_eq!(extract_cards("Q: What is {{this}}?\nA: A question\n"), vec![card("What is {{this}}?", "A question")]);
+    }
+
+    #[test]
+    fn first_reviews() {
+        let first = Schedule::default().next(Grade::Good);
+        assert_schedule(first, 2.5, 1, 1);
+        let second = first.next(Grade::Good);
+        assert_schedule(second, 2.5, 6, 2);
+        let third = second.next(Grade::Good);
+        assert_schedule(third, 2.5, 15, 3);
+    }
+
+    #[test]
+    fn ease_changes_with_grade() {
+        let schedule = Schedule { ease: 2.5, interval: 15, repetitions: 3 };
+        // The new interval uses the ease from before the review.
+        assert_schedule(schedule.next(Grade::Easy), 2.6, 38, 4);
+        assert_schedule(schedule.next(Grade::Good), 2.5, 38, 4);
+        assert_schedule(schedule.next(Grade::Hard), 2.36, 38, 4);
+    }
+
+    #[test]
+    fn lapses_start_over() {
+        let schedule = Schedule { ease: 2.5, interval: 15, repetitions: 3 };
+        assert_schedule(schedule.next(Grade::Again), 1.96, 1, 0);
+        assert_schedule(schedule.next(Grade::Again).next(Grade::Good), 1.96, 1, 1);
+    }
+
+    #[test]
+    fn ease_has_a_floor() {
+        let schedule = Schedule { ease: 1.4, interval: 6, repetitions: 2 };
+        assert_schedule(schedule.next(Grade::Again), 1.3, 1, 0);
+        assert_schedule(schedule.next(Grade::Hard), 1.3, 8, 3);
+    }
+}
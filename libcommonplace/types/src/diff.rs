@@ -0,0 +1,147 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+
+// Line based diffing, using Myers' algorithm. Indices in DiffOp are line indices into the old and
+// new texts respectively.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiffOp {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", content = "text")]
+pub enum DiffLine {
+    Context(String),
+    Added(String),
+    Removed(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiffHunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+impl fmt::Display for DiffHunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "@@ -{},{} +{},{} @@", self.old_start, self.old_len, self.new_start, self.new_len)?;
+        for line in &self.lines {
+            match line {
+                DiffLine::Context(text) => writeln!(f, " {}", text)?,
+                DiffLine::Added(text) => writeln!(f, "+{}", text)?,
+                DiffLine::Removed(text) => writeln!(f, "-{}", text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn diff_ops<T: PartialEq>(old: &[T], new: &[T]) -> Vec<DiffOp> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace = vec![];
+
+    'outer: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) { v[i + 1] } else { v[i - 1] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'outer;
+            }
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[(k - 1 + offset) as usize] < v[(k + 1 + offset) as usize]) { k + 1 } else { k - 1 };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            ops.push(DiffOp::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                ops.push(DiffOp::Insert(prev_y as usize));
+            } else {
+                ops.push(DiffOp::Delete(prev_x as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    ops.reverse();
+    ops
+}
+
+pub fn diff_lines(old: &str, new: &str, context: usize) -> Vec<DiffHunk> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = diff_ops(&old_lines, &new_lines);
+    let is_change = |op: &DiffOp| !matches!(op, DiffOp::Equal(_, _));
+
+    // Line positions in the old and new texts just before each op.
+    let mut positions = vec![];
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for op in &ops {
+        positions.push((old_pos, new_pos));
+        match op {
+            DiffOp::Equal(_, _) => { old_pos += 1; new_pos += 1; },
+            DiffOp::Delete(_) => old_pos += 1,
+            DiffOp::Insert(_) => new_pos += 1,
+        }
+    }
+    positions.push((old_pos, new_pos));
+
+    let mut hunks = vec![];
+    let mut pos = 0;
+    while let Some(first_change) = ops[pos..].iter().position(is_change).map(|x| x + pos) {
+        let start = std::cmp::max(first_change.saturating_sub(context), pos);
+        let mut end = first_change;
+        loop {
+            while end < ops.len() && is_change(&ops[end]) {
+                end += 1;
+            }
+            match ops[end..].iter().position(is_change) {
+                Some(gap) if gap <= 2 * context => end += gap,
+                _ => break,
+            }
+        }
+        let end = std::cmp::min(end + context, ops.len());
+
+        hunks.push(DiffHunk {
+            old_start: positions[start].0 + 1,
+            old_len: positions[end].0 - positions[start].0,
+            new_start: positions[start].1 + 1,
+            new_len: positions[end].1 - positions[start].1,
+            lines: ops[start..end].iter().map(|op| match op {
+                DiffOp::Equal(i, _) => DiffLine::Context(old_lines[*i].to_string()),
+                DiffOp::Delete(i) => DiffLine::Removed(old_lines[*i].to_string()),
+                DiffOp::Insert(i) => DiffLine::Added(new_lines[*i].to_string()),
+            }).collect(),
+        });
+        pos = end;
+    }
+
+    hunks
+}
@@ -1,7 +1,21 @@
 use std::fmt;
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+
+mod query;
+mod diff;
+mod tag_forest;
+mod anchor;
+mod cards;
+mod merge;
+mod oplog;
+pub use tag_forest::{ROOT_TAG, TagNode, TagForest, NoteOrTag};
+pub use query::{Query, QueryError, parse_query};
+pub use diff::{DiffOp, DiffLine, DiffHunk, diff_ops, diff_lines};
+pub use anchor::{Anchor, AnchorError, Rect};
+pub use cards::{CardId, CardText, Card, ChangedCard, Review, Grade, Schedule, extract_cards, card_similarity};
+pub use merge::merge_lines;
+pub use oplog::{Operation, LogEntry};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(transparent)]
@@ -27,19 +41,46 @@ impl fmt::Display for TagId {
     }
 }
 
-#[derive(Debug)]
-pub struct TagRow {
-    pub id: TagId,
-    pub name: String,
-    pub parent: Option<TagId>,
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct LinkId {
+    pub uuid: Uuid,
+}
+
+impl fmt::Display for LinkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.uuid)
+    }
+}
+
+// A hybrid logical clock reading, and the device that made it. `hlc` is milliseconds since the
+// epoch shifted left by 16 bits, plus a counter for changes made in the same millisecond, or after
+// seeing a change from a device whose clock is ahead. Timestamps compare by hlc, and then by device,
+// so any two changes are in the same order on every device. The device is None for changes made
+// before timestamps were recorded.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub hlc: i64,
+    pub device: Option<Uuid>,
+}
+
+impl Timestamp {
+    pub const COUNTER_BITS: u32 = 16;
+
+    pub fn from_millis(millis: i64) -> Timestamp {
+        Timestamp { hlc: millis << Timestamp::COUNTER_BITS, device: None }
+    }
+
+    pub fn millis(&self) -> i64 {
+        self.hlc >> Timestamp::COUNTER_BITS
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct TagTree {
+pub struct TagRow {
     pub id: TagId,
     pub name: String,
-    pub children: Vec<TagTree>,
-    pub notes: Vec<NoteId>,
+    pub parent: Option<TagId>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -50,118 +91,100 @@ pub struct Note {
     pub mimetype: String,
 }
 
-#[derive(Debug, Clone)]
-pub enum NoteOrTag<'a> {
-    Note(&'a Note),
-    Tag(&'a TagTree),
+// A past version of a note. `time` is when this version was replaced by a newer one, not when it
+// was created.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NoteHistoryEntry {
+    pub note_id: NoteId,
+    pub hash: [u8; 32],
+    pub name: String,
+    pub mimetype: String,
+    pub time: i64,
 }
 
-impl From<&TagRow> for TagTree {
-    fn from(tag_row: &TagRow) -> Self {
-        TagTree {
-            id: tag_row.id,
-            name: tag_row.name.clone(),
-            children: vec![],
-            notes: vec![],
-        }
-    }
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchHit {
+    pub note_id: NoteId,
+    pub name: String,
+    pub snippet: String,
+    pub rank: f64,
+}
+
+// The kind of link that the GUI shows as an annotation of the note it links to.
+pub const ANNOTATION_LINK: &str = "annotation";
+
+// Links point either at another note, or at something outside of the repository, like a website.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LinkTarget {
+    Note(NoteId),
+    Url(String),
 }
 
-impl TagTree {
-    fn pretty_print(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
-        write!(f, "{}{}: {:?}\n", " ".repeat(depth), self.name, self.notes)?;
-        for child in &self.children {
-            child.pretty_print(f, depth + 1)?
+impl fmt::Display for LinkTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkTarget::Note(note) => write!(f, "{}", note),
+            LinkTarget::Url(url) => write!(f, "{}", url),
         }
-        Ok(())
     }
 }
 
-impl fmt::Display for TagTree {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.pretty_print(f, 0)
-    }
+// A directed link from a note to its target. The anchors narrow down which part of each note the
+// link is about, and are None if it's about the note as a whole. URLs carry their own fragments,
+// so to_anchor is always None for them.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Link {
+    pub id: LinkId,
+    pub from: NoteId,
+    pub from_anchor: Option<Anchor>,
+    pub to: LinkTarget,
+    pub to_anchor: Option<Anchor>,
+    pub kind: String,
 }
 
-pub fn get_by_uuid<'a>(tag_tree: &'a Vec<TagTree>, notes: &'a HashMap<Uuid, Note>, uuid: Uuid) -> Option<NoteOrTag<'a>> {
-    if let Some(note) = notes.get(&uuid) {
-        return Some(NoteOrTag::Note(&note));
-    }
-    for tree in tag_tree {
-        if tree.id.uuid == uuid {
-            return Some(NoteOrTag::Tag(&tree));
-        }
-        if let Some(ret) = get_by_uuid(&tree.children, notes, uuid) {
-            return Some(ret);
-        }
-    }
-    None
-}
-
-pub fn get_by_full_name<'a>(tag_tree: &'a Vec<TagTree>, notes: &'a HashMap<Uuid, Note>, name: Vec<String>) -> Option<NoteOrTag<'a>> {
-    if let Some((head, tail)) = name.split_first() {
-        for tag_tree in tag_tree {
-            if tail.len() == 0 {
-                for note_id in &tag_tree.notes {
-                    if let Some(note) = notes.get(&note_id.uuid) {
-                        if &note.name == head {
-                            return Some(NoteOrTag::Note(&note));
-                        }
-                    }
-                }
-            }
-            if &tag_tree.name == head {
-                if tail.len() == 0 {
-                    return Some(NoteOrTag::Tag(&tag_tree));
-                } else {
-                    return get_by_full_name(&tag_tree.children, notes, tail.to_vec())
-                }
-            }
-        }
-    }
-    None
+// The body of a request to add a link, which doesn't have an id yet.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NewLink {
+    pub from_anchor: Option<Anchor>,
+    pub to: LinkTarget,
+    pub to_anchor: Option<Anchor>,
+    pub kind: String,
 }
 
-pub fn get_tags_for_note(tag_tree: &Vec<TagTree>, note: &NoteId) -> Vec<TagId> {
-    let mut out = vec![];
-    for tag_tree in tag_tree {
-        for try_note in &tag_tree.notes {
-            if try_note == note {
-                out.push(tag_tree.id);
-            }
-        }
-        out.append(&mut get_tags_for_note(&tag_tree.children, note));
-    }
+// An anchor checked against the note it's in, with what's needed to show the note at that spot.
+// Text anchors are clamped to the current contents of the note, since the note may have been
+// edited since the anchor was made, and `line` is the zero-based line the anchor starts on.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResolvedAnchor {
+    pub note: NoteId,
+    pub mimetype: String,
+    pub anchor: Anchor,
+    pub line: Option<u64>,
+}
 
-    out
+// Everything a repository has that's worth not sending it again when syncing with it: the hashes
+// of its blobs, and each row of its history as (note, hash, time).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Inventory {
+    pub blobs: Vec<[u8; 32]>,
+    pub history: Vec<(NoteId, [u8; 32], i64)>,
 }
 
-pub fn get_tag_name(tag_tree: &Vec<TagTree>, tag: &TagId) -> Option<Vec<String>> {
-    for tag_tree in tag_tree {
-        let mut out = vec![];
-        out.push(tag_tree.name.clone());
-        if &tag_tree.id == tag {
-            return Some(out)
-        }
-        if let Some(mut name) = get_tag_name(&tag_tree.children, tag) {
-            out.append(&mut name);
-            return Some(out);
-        }
-    }
-    return None
-}
-
-pub fn get_tag_by_full_name(tag_tree: &Vec<TagTree>, name: Vec<&str>) -> Option<TagId> {
-    if let Some((head, tail)) = name.split_first() {
-        for tag_tree in tag_tree {
-            if &tag_tree.name == head {
-                if tail.len() == 0 {
-                    return Some(tag_tree.id);
-                } else {
-                    return get_tag_by_full_name(&tag_tree.children, tail.to_vec())
-                }
-            }
-        }
-    }
-    None
+// A note that was edited on two devices in ways that couldn't be merged. The note's current version
+// is whichever head was modified last, and `base` is the version both heads were edited from.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Conflict {
+    pub note_id: NoteId,
+    pub base: [u8; 32],
+    pub ours: [u8; 32],
+    pub theirs: [u8; 32],
+    pub time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Resolution {
+    // Keep one of the versions in the conflict, by hash.
+    Keep([u8; 32]),
+    // Replace them with new contents, usually merged by hand.
+    Merged(Vec<u8>),
 }
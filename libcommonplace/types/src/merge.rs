@@ -0,0 +1,135 @@
+use crate::{DiffOp, diff_ops};
+
+// Three-way merging of text, a line at a time, like diff3. Each side is diffed against the base,
+// and the lines that are unchanged on both sides split the texts into chunks. A chunk that only
+// one side changed takes that side's version, and a chunk that both sides changed in the same way
+// takes either. A chunk that both sides changed differently is a conflict, and there's no merge.
+//
+// Lines keep their line endings, so a missing newline at the end of the text is a change like any
+// other. Where both sides insert lines at the same spot, it's a conflict, so the result doesn't
+// depend on which side is which.
+
+// For each line of the base, the line of the other text that it's unchanged in, if any.
+fn matching_lines(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    for op in diff_ops(base, other) {
+        if let DiffOp::Equal(i, j) = op {
+            matches[i] = Some(j);
+        }
+    }
+    matches
+}
+
+pub fn merge_lines(base: &str, a: &str, b: &str) -> Option<String> {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let a: Vec<&str> = a.split_inclusive('\n').collect();
+    let b: Vec<&str> = b.split_inclusive('\n').collect();
+    let a_matches = matching_lines(&base, &a);
+    let b_matches = matching_lines(&base, &b);
+
+    let mut out: Vec<&str> = vec![];
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // The next base line that's unchanged on both sides, or the end of all three texts.
+        let next = (i..base.len()).find_map(|x| match (a_matches[x], b_matches[x]) {
+            (Some(y), Some(z)) => Some((x, y, z)),
+            _ => None,
+        });
+        let (x, y, z) = next.unwrap_or((base.len(), a.len(), b.len()));
+
+        if (x, y, z) == (i, j, k) {
+            if x == base.len() {
+                break;
+            }
+            out.push(base[x]);
+            i += 1;
+            j += 1;
+            k += 1;
+            continue;
+        }
+
+        let (base_chunk, a_chunk, b_chunk) = (&base[i..x], &a[j..y], &b[k..z]);
+        if a_chunk == base_chunk || a_chunk == b_chunk {
+            out.extend(b_chunk);
+        } else if b_chunk == base_chunk {
+            out.extend(a_chunk);
+        } else {
+            return None;
+        }
+        i = x;
+        j = y;
+        k = z;
+    }
+    Some(out.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "one\ntwo\nthree\nfour\n";
+
+    fn merge(a: &str, b: &str) -> Option<String> {
+        merge_lines(BASE, a, b)
+    }
+
+    #[test]
+    fn unchanged() {
+        assert_eq!(merge(BASE, BASE).as_deref(), Some(BASE));
+    }
+
+    #[test]
+    fn one_side_changed() {
+        let a = "one\nTWO\nthree\nfour\n";
+        assert_eq!(merge(a, BASE).as_deref(), Some(a));
+        assert_eq!(merge(BASE, a).as_deref(), Some(a));
+    }
+
+    #[test]
+    fn non_overlapping_edits() {
+        let a = "ONE\ntwo\nthree\nfour\n";
+        let b = "one\ntwo\nthree\nFOUR\n";
+        assert_eq!(merge(a, b).as_deref(), Some("ONE\ntwo\nthree\nFOUR\n"));
+        assert_eq!(merge(b, a).as_deref(), Some("ONE\ntwo\nthree\nFOUR\n"));
+    }
+
+    #[test]
+    fn deletion_and_edit() {
+        let a = "one\nthree\nfour\n";
+        let b = "one\ntwo\nthree\nFOUR\n";
+        assert_eq!(merge(a, b).as_deref(), Some("one\nthree\nFOUR\n"));
+    }
+
+    #[test]
+    fn identical_edits() {
+        let a = "one\nTWO\nthree\nfour\nfive\n";
+        assert_eq!(merge(a, a).as_deref(), Some(a));
+    }
+
+    #[test]
+    fn conflicting_edits() {
+        assert_eq!(merge("one\nTWO\nthree\nfour\n", "one\nDEUX\nthree\nfour\n"), None);
+        assert_eq!(merge("one\nthree\nfour\n", "one\nTWO\nthree\nfour\n"), None);
+    }
+
+    #[test]
+    fn insertions_at_start_and_end() {
+        let start = "zero\none\ntwo\nthree\nfour\n";
+        let end = "one\ntwo\nthree\nfour\nfive\n";
+        assert_eq!(merge(start, end).as_deref(), Some("zero\none\ntwo\nthree\nfour\nfive\n"));
+        assert_eq!(merge(end, start).as_deref(), Some("zero\none\ntwo\nthree\nfour\nfive\n"));
+    }
+
+    #[test]
+    fn insertions_at_the_same_spot_conflict() {
+        assert_eq!(merge("zero\none\ntwo\nthree\nfour\n", "nought\none\ntwo\nthree\nfour\n"), None);
+        assert_eq!(merge("one\ntwo\nthree\nfour\nfive\n", "one\ntwo\nthree\nfour\nsix\n"), None);
+    }
+
+    #[test]
+    fn missing_final_newline() {
+        let a = "one\ntwo\nthree\nfour";
+        let b = "ONE\ntwo\nthree\nfour\n";
+        assert_eq!(merge(a, b).as_deref(), Some("ONE\ntwo\nthree\nfour"));
+    }
+}
@@ -0,0 +1,75 @@
+use serde::{Serialize, Deserialize};
+use crate::{Card, CardId, Link, Note, NoteId, Review, Schedule, TagId, TagRow, Timestamp};
+
+// A change that was made to a repository. Each one has what it replaced as well as what it
+// replaced it with, so that it can be undone.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Operation {
+    AddNote { note: Note },
+    UpdateNote { note: NoteId, from: [u8; 32], to: [u8; 32] },
+    RenameNote { note: NoteId, from: String, to: String },
+    RestoreNoteVersion { from: Note, to: Note },
+    DeleteNote { note: Note },
+    ResolveConflict { note: NoteId, from: [u8; 32], to: [u8; 32] },
+    // Only imports record conflicts.
+    RecordConflict { note: NoteId, base: [u8; 32], ours: [u8; 32], theirs: [u8; 32] },
+    CreateTag { tag: TagRow },
+    RenameTag { tag: TagId, from: String, to: String },
+    MoveTag { tag: TagId, from: Option<TagId>, to: Option<TagId> },
+    // src is deleted, after its notes and children are moved to dst.
+    MergeTags { src: TagRow, dst: TagId },
+    // Notes tagged with the tag or its descendants are untagged first. Entries logged before
+    // deleting a tag did that don't have them.
+    DeleteTag {
+        tag: TagRow,
+        #[serde(default)]
+        untagged: Vec<(NoteId, TagId)>,
+    },
+    SetTagHidden { tag: TagId, hidden: bool },
+    TagNote { note: NoteId, tag: TagId },
+    UntagNote { note: NoteId, tag: TagId },
+    AddLink { link: Link },
+    DeleteLink { link: Link },
+    ReviewCard { card: CardId, quality: u8, from: Schedule, to: Schedule },
+    DeleteCard { card: Card, reviews: Vec<Review> },
+    // Changes from another repository, merged in by importing a bundle, in the order they were
+    // made. Entries logged before the changes were recorded only have the note and tag counts.
+    ImportBundle {
+        notes_added: usize, notes_updated: usize, notes_merged: usize, notes_deleted: usize, tags: usize,
+        #[serde(default)]
+        conflicts: usize,
+        // Rows added to NoteHistory and Blobs, which aren't listed in changes.
+        #[serde(default)]
+        history: usize,
+        #[serde(default)]
+        blobs: usize,
+        #[serde(default)]
+        changes: Vec<Operation>,
+    },
+    // Rows that referred to missing notes or tags, removed by fsck.
+    RepairRepository { changes: Vec<Operation> },
+    // Unreferenced blobs, whose contents are gone, so this is the one operation that can't be
+    // undone.
+    CollectGarbage { blobs: Vec<[u8; 32]> },
+}
+
+impl Operation {
+    // The note the operation is about, if any.
+    pub fn note(&self) -> Option<NoteId> {
+        match self {
+            Operation::AddNote { note } | Operation::DeleteNote { note } => Some(NoteId { uuid: note.id }),
+            Operation::RestoreNoteVersion { to, .. } => Some(NoteId { uuid: to.id }),
+            Operation::UpdateNote { note, .. } | Operation::RenameNote { note, .. } | Operation::ResolveConflict { note, .. } | Operation::RecordConflict { note, .. }
+                | Operation::TagNote { note, .. } | Operation::UntagNote { note, .. } => Some(*note),
+            Operation::AddLink { link } | Operation::DeleteLink { link } => Some(link.from),
+            Operation::DeleteCard { card, .. } => Some(card.note_id),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogEntry {
+    pub time: Timestamp,
+    pub operation: Operation,
+}
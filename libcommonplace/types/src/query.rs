@@ -0,0 +1,282 @@
+use std::fmt;
+use crate::Note;
+
+// The query language looks like:
+//
+//   tag:book (tag:programming>languages>rust OR NOT mime:text/*) name:"reading list"
+//
+// Terms next to each other are ANDed together, NOT binds tighter than AND, and AND binds tighter
+// than OR. `tag:` matches a tag and all of its descendants, `name:` is a case-insensitive
+// substring match on the note name, and `mime:` is an exact match, or a prefix match if it ends
+// in `*`.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    All,
+    Tag(Vec<String>),
+    Name(String),
+    Mime(String),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryError {
+    pub message: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid query: {}", self.message)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+fn query_error<T>(message: String) -> Result<T, QueryError> {
+    Err(QueryError { message })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Filter(String, String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' {
+            chars.next();
+            tokens.push(Token::LParen);
+        } else if c == ')' {
+            chars.next();
+            tokens.push(Token::RParen);
+        } else {
+            let mut word = String::new();
+            let mut quoted = false;
+            while let Some(&c) = chars.peek() {
+                if c == '"' {
+                    quoted = !quoted;
+                } else if !quoted && (c.is_whitespace() || c == '(' || c == ')') {
+                    break;
+                } else {
+                    word.push(c);
+                }
+                chars.next();
+            }
+            if quoted {
+                return query_error(format!("unterminated quote in \"{}\"", word));
+            }
+
+            tokens.push(match word.as_str() {
+                "AND" => Token::And,
+                "OR" => Token::Or,
+                "NOT" => Token::Not,
+                _ => match word.find(':') {
+                    Some(i) => Token::Filter(word[..i].to_string(), word[i + 1..].to_string()),
+                    None => return query_error(format!("expected tag:, name: or mime: filter, found \"{}\"", word)),
+                },
+            });
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            query = Query::Or(Box::new(query), Box::new(self.parse_and()?));
+        }
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => { self.next(); },
+                Some(Token::Not) | Some(Token::LParen) | Some(Token::Filter(_, _)) => {},
+                _ => break,
+            }
+            query = Query::And(Box::new(query), Box::new(self.parse_unary()?));
+        }
+        Ok(query)
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, QueryError> {
+        match self.next() {
+            Some(Token::Not) => Ok(Query::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let query = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(query),
+                    _ => query_error("missing closing parenthesis".to_string()),
+                }
+            },
+            Some(Token::Filter(key, value)) => {
+                if value.is_empty() {
+                    return query_error(format!("empty value for {}: filter", key));
+                }
+                match key.as_str() {
+                    "tag" => Ok(Query::Tag(value.split('>').map(|x| x.to_string()).collect())),
+                    "name" => Ok(Query::Name(value)),
+                    "mime" => Ok(Query::Mime(value)),
+                    _ => query_error(format!("unknown filter \"{}:\"", key)),
+                }
+            },
+            Some(token) => query_error(format!("unexpected {:?}", token)),
+            None => query_error("unexpected end of query".to_string()),
+        }
+    }
+}
+
+pub fn parse_query(s: &str) -> Result<Query, QueryError> {
+    let mut parser = Parser { tokens: tokenize(s)?, pos: 0 };
+    if parser.peek().is_none() {
+        return Ok(Query::All);
+    }
+    let query = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(query),
+        Some(token) => query_error(format!("unexpected {:?}", token)),
+    }
+}
+
+impl Query {
+    // `tags` is the full path of every tag the note is tagged with.
+    pub fn matches(&self, note: &Note, tags: &[Vec<String>]) -> bool {
+        match self {
+            Query::All => true,
+            Query::Tag(path) => tags.iter().any(|tag| tag.starts_with(path)),
+            Query::Name(name) => note.name.to_lowercase().contains(&name.to_lowercase()),
+            Query::Mime(mime) => match mime.strip_suffix('*') {
+                Some(prefix) => note.mimetype.starts_with(prefix),
+                None => &note.mimetype == mime,
+            },
+            Query::And(a, b) => a.matches(note, tags) && b.matches(note, tags),
+            Query::Or(a, b) => a.matches(note, tags) || b.matches(note, tags),
+            Query::Not(a) => !a.matches(note, tags),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(path: &str) -> Query {
+        Query::Tag(path.split('>').map(|x| x.to_string()).collect())
+    }
+
+    fn and(a: Query, b: Query) -> Query {
+        Query::And(Box::new(a), Box::new(b))
+    }
+
+    fn or(a: Query, b: Query) -> Query {
+        Query::Or(Box::new(a), Box::new(b))
+    }
+
+    fn not(a: Query) -> Query {
+        Query::Not(Box::new(a))
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(parse_query("   "), Ok(Query::All));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expected = or(tag("a"), and(tag("b"), tag("c")));
+        assert_eq!(parse_query("tag:a OR tag:b AND tag:c"), Ok(expected.clone()));
+        assert_eq!(parse_query("tag:a OR tag:b tag:c"), Ok(expected));
+        assert_eq!(parse_query("tag:a tag:b OR tag:c"), Ok(or(and(tag("a"), tag("b")), tag("c"))));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        assert_eq!(parse_query("NOT tag:a tag:b"), Ok(and(not(tag("a")), tag("b"))));
+        assert_eq!(parse_query("NOT NOT tag:a"), Ok(not(not(tag("a")))));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(parse_query("(tag:a OR tag:b) tag:c"), Ok(and(or(tag("a"), tag("b")), tag("c"))));
+        assert_eq!(parse_query("NOT (tag:a OR tag:b)"), Ok(not(or(tag("a"), tag("b")))));
+        assert_eq!(parse_query("tag:a(tag:b)"), Ok(and(tag("a"), tag("b"))));
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(parse_query("tag:programming>languages>rust"), Ok(tag("programming>languages>rust")));
+        assert_eq!(parse_query("name:list"), Ok(Query::Name("list".to_string())));
+        assert_eq!(parse_query("mime:text/*"), Ok(Query::Mime("text/*".to_string())));
+    }
+
+    #[test]
+    fn quotes_keep_spaces_and_parentheses() {
+        assert_eq!(parse_query("name:\"reading list\""), Ok(Query::Name("reading list".to_string())));
+        assert_eq!(parse_query("tag:\"science fiction (old)\">authors"), Ok(tag("science fiction (old)>authors")));
+        assert_eq!(parse_query("tag:\"OR\""), Ok(tag("OR")));
+    }
+
+    #[test]
+    fn malformed_queries() {
+        for query in &[
+            "tag:a OR",
+            "AND tag:a",
+            "(tag:a",
+            "tag:a)",
+            "()",
+            "NOT",
+            "rust",
+            "tag:",
+            "size:10",
+            "name:\"reading list",
+        ] {
+            assert!(parse_query(query).is_err(), "{} should be an error", query);
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let note = Note { id: uuid::Uuid::new_v4(), hash: [0; 32], name: "Reading List".to_string(), mimetype: "text/markdown".to_string() };
+        let tags = vec![vec!["books".to_string(), "fiction".to_string()]];
+        let matches = |query: &str| parse_query(query).unwrap().matches(&note, &tags);
+        assert!(matches("tag:books"));
+        assert!(matches("tag:books>fiction"));
+        assert!(!matches("tag:fiction"));
+        assert!(matches("name:list mime:text/*"));
+        assert!(!matches("mime:text/plain"));
+        assert!(matches("NOT tag:films OR tag:films"));
+        assert!(!matches("NOT (tag:books OR tag:films)"));
+    }
+}
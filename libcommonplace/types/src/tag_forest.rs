@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+use crate::{TagId, NoteId, TagRow, Note};
+
+// All of the tags in a repository, hanging off of a single root node with the id ROOT_TAG. The
+// tree is built once from the Tags and TagMap rows, and indexed so that looking up a tag, its
+// parent, its full name, or the tags on a note doesn't need to walk the tree.
+//
+// It's serialized as the rows it was built from, and the indices are rebuilt on deserialization.
+
+pub const ROOT_TAG: TagId = TagId { uuid: Uuid::nil() };
+
+#[derive(Clone, Debug)]
+pub struct TagNode {
+    pub id: TagId,
+    pub name: String,
+    pub parent: Option<TagId>,
+    pub children: Vec<TagId>,
+    pub notes: Vec<NoteId>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "TagForestRows", into = "TagForestRows")]
+pub struct TagForest {
+    nodes: HashMap<TagId, TagNode>,
+    names: HashMap<TagId, Vec<String>>,
+    by_name: HashMap<Vec<String>, TagId>,
+    note_tags: HashMap<NoteId, Vec<TagId>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct TagForestRows {
+    tags: Vec<TagRow>,
+    tag_map: Vec<(TagId, NoteId)>,
+}
+
+impl From<TagForestRows> for TagForest {
+    fn from(rows: TagForestRows) -> Self {
+        TagForest::new(rows.tags, rows.tag_map)
+    }
+}
+
+impl From<TagForest> for TagForestRows {
+    fn from(forest: TagForest) -> Self {
+        let mut tags = vec![];
+        let mut tag_map = vec![];
+        forest.walk(ROOT_TAG, &mut |node| {
+            if node.id != ROOT_TAG {
+                tags.push(TagRow {
+                    id: node.id,
+                    name: node.name.clone(),
+                    parent: node.parent.filter(|x| *x != ROOT_TAG),
+                });
+            }
+            tag_map.extend(node.notes.iter().map(|note| (node.id, *note)));
+        });
+        TagForestRows { tags, tag_map }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum NoteOrTag<'a> {
+    Note(&'a Note),
+    Tag(&'a TagNode),
+}
+
+impl TagForest {
+    // Tags whose parent is missing, or that are part of a cycle, can't be reached from the root,
+    // and are left out.
+    pub fn new(tag_rows: Vec<TagRow>, tagmap_rows: Vec<(TagId, NoteId)>) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(ROOT_TAG, TagNode {
+            id: ROOT_TAG,
+            name: String::new(),
+            parent: None,
+            children: vec![],
+            notes: vec![],
+        });
+        for tag_row in &tag_rows {
+            nodes.insert(tag_row.id, TagNode {
+                id: tag_row.id,
+                name: tag_row.name.clone(),
+                parent: Some(tag_row.parent.unwrap_or(ROOT_TAG)),
+                children: vec![],
+                notes: vec![],
+            });
+        }
+        for tag_row in &tag_rows {
+            if let Some(parent) = nodes.get_mut(&tag_row.parent.unwrap_or(ROOT_TAG)) {
+                parent.children.push(tag_row.id);
+            }
+        }
+
+        let mut names: HashMap<TagId, Vec<String>> = HashMap::new();
+        names.insert(ROOT_TAG, vec![]);
+        let mut stack = vec![ROOT_TAG];
+        while let Some(id) = stack.pop() {
+            let name = names[&id].clone();
+            for child in &nodes[&id].children {
+                let mut child_name = name.clone();
+                child_name.push(nodes[child].name.clone());
+                names.insert(*child, child_name);
+                stack.push(*child);
+            }
+        }
+        nodes.retain(|id, _| names.contains_key(id));
+        names.remove(&ROOT_TAG);
+        let by_name = names.iter().map(|(id, name)| (name.clone(), *id)).collect();
+
+        let mut note_tags: HashMap<NoteId, Vec<TagId>> = HashMap::new();
+        for (tag, note) in tagmap_rows {
+            if let Some(node) = nodes.get_mut(&tag) {
+                node.notes.push(note);
+                note_tags.entry(note).or_default().push(tag);
+            }
+        }
+
+        TagForest { nodes, names, by_name, note_tags }
+    }
+
+    fn walk<F: FnMut(&TagNode)>(&self, id: TagId, f: &mut F) {
+        if let Some(node) = self.nodes.get(&id) {
+            f(node);
+            for child in &node.children {
+                self.walk(*child, f);
+            }
+        }
+    }
+
+    pub fn root(&self) -> &TagNode {
+        &self.nodes[&ROOT_TAG]
+    }
+
+    pub fn get(&self, tag: &TagId) -> Option<&TagNode> {
+        self.nodes.get(tag)
+    }
+
+    pub fn children(&self, tag: &TagId) -> Vec<&TagNode> {
+        match self.nodes.get(tag) {
+            Some(node) => node.children.iter().map(|x| &self.nodes[x]).collect(),
+            None => vec![],
+        }
+    }
+
+    // The tag, and all of the tags underneath it.
+    pub fn descendants(&self, tag: &TagId) -> Vec<TagId> {
+        let mut out = vec![];
+        self.walk(*tag, &mut |node| out.push(node.id));
+        out
+    }
+
+    pub fn parent(&self, tag: &TagId) -> Option<TagId> {
+        self.nodes.get(tag).and_then(|x| x.parent).filter(|x| *x != ROOT_TAG)
+    }
+
+    // The names of the tag and each of its ancestors, starting from the root.
+    pub fn tag_name(&self, tag: &TagId) -> Option<&Vec<String>> {
+        self.names.get(tag)
+    }
+
+    pub fn tag_by_full_name(&self, name: &[&str]) -> Option<TagId> {
+        let name: Vec<String> = name.iter().map(|x| x.to_string()).collect();
+        self.by_name.get(&name).cloned()
+    }
+
+    // The ids of the tag and each of its ancestors, starting from the root.
+    pub fn tag_path(&self, tag: &TagId) -> Option<Vec<TagId>> {
+        if !self.names.contains_key(tag) {
+            return None;
+        }
+        let mut path = vec![*tag];
+        while let Some(parent) = self.parent(path.last().unwrap()) {
+            path.push(parent);
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    pub fn tags_for_note(&self, note: &NoteId) -> &[TagId] {
+        self.note_tags.get(note).map(|x| &x[..]).unwrap_or(&[])
+    }
+
+    // Removes tags that are ancestors of other tags in the set, since they don't tell you anything
+    // more — a note tagged with both misc>foo and misc only needs to show misc>foo.
+    pub fn canonicalize_tags(&self, tags: &[TagId]) -> Vec<TagId> {
+        let ancestors: Vec<TagId> = tags.iter().filter_map(|tag| self.tag_path(tag)).flat_map(|mut path| {
+            path.pop();
+            path
+        }).collect();
+        tags.iter().filter(|tag| !ancestors.contains(tag)).cloned().collect()
+    }
+
+    pub fn get_by_uuid<'a>(&'a self, notes: &'a HashMap<Uuid, Note>, uuid: Uuid) -> Option<NoteOrTag<'a>> {
+        if let Some(note) = notes.get(&uuid) {
+            return Some(NoteOrTag::Note(note));
+        }
+        self.nodes.get(&TagId { uuid }).filter(|x| x.id != ROOT_TAG).map(NoteOrTag::Tag)
+    }
+
+    // The name is either the full name of a tag, or the full name of a tag followed by the name of
+    // a note with that tag.
+    pub fn get_by_full_name<'a>(&'a self, notes: &'a HashMap<Uuid, Note>, name: Vec<String>) -> Option<NoteOrTag<'a>> {
+        if let Some(tag) = self.by_name.get(&name) {
+            return Some(NoteOrTag::Tag(&self.nodes[tag]));
+        }
+        let (note_name, tag_name) = name.split_last()?;
+        let tag = self.by_name.get(tag_name)?;
+        self.nodes[tag].notes.iter()
+            .filter_map(|note| notes.get(&note.uuid))
+            .find(|note| &note.name == note_name)
+            .map(NoteOrTag::Note)
+    }
+
+    fn pretty_print(&self, f: &mut fmt::Formatter, tag: &TagId, depth: usize) -> fmt::Result {
+        for child in self.children(tag) {
+            writeln!(f, "{}{}: {:?}", " ".repeat(depth), child.name, child.notes)?;
+            self.pretty_print(f, &child.id, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TagForest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.pretty_print(f, &ROOT_TAG, 0)
+    }
+}
@@ -0,0 +1,196 @@
+use structopt::StructOpt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use libcommonplace::{open_db, find_repo, get_inventory, export_bundle_for, import_bundle, ImportReport, Inventory, CommonplaceError, Connection};
+
+// Syncs two repositories directly over TCP. One side runs `serve`, and the other connects to it
+// with `sync`. Each message is a big-endian u64 length followed by that many bytes, and a sync is
+// always the same five messages:
+//
+//   client -> server   the client's inventory, as JSON
+//   server -> client   the server's inventory, as JSON
+//   server -> client   a bundle of what the client doesn't have
+//   client -> server   a bundle of what the server doesn't have
+//   server -> client   what the server imported, or why it couldn't
+//
+// The bundles are the same as the ones export-bundle writes, so they're merged the same way. The
+// client writes its bundle before importing the server's, so that both sides merge the same two
+// heads, and end up with the same merge or the same conflict.
+// There's no authentication or encryption, so only serve on networks you trust.
+
+// How long either side waits for the other to send or accept more of a message before giving up.
+const TIMEOUT: Duration = Duration::from_secs(120);
+// Messages longer than this are refused before any of them is read. Repositories with bundles
+// bigger than this have to be synced with export-bundle and import-bundle instead.
+const MAX_MESSAGE_LEN: u64 = 1 << 30;
+
+#[derive(StructOpt)]
+struct Args {
+    /// A repository name from the config file, or a path to a repository
+    #[structopt(long, global = true)]
+    repo: Option<String>,
+    #[structopt(subcommand)]
+    cmdline: Cmdline,
+}
+
+#[derive(StructOpt)]
+enum Cmdline {
+    /// Waits for other repositories to sync with this one
+    Serve {
+        #[structopt(long, default_value = "localhost:38842")]
+        address: String,
+    },
+    /// Syncs with a repository that's being served
+    Sync {
+        #[structopt(default_value = "localhost:38842")]
+        address: String,
+    },
+}
+
+fn write_message(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    if message.len() as u64 > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("message is longer than {} bytes", MAX_MESSAGE_LEN)));
+    }
+    stream.write_all(&(message.len() as u64).to_be_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()
+}
+
+fn read_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_be_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message is longer than {} bytes", MAX_MESSAGE_LEN)));
+    }
+    let mut message = vec![];
+    stream.take(len).read_to_end(&mut message)?;
+    if message.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a message"));
+    }
+    Ok(message)
+}
+
+fn set_timeouts(stream: &TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))
+}
+
+fn inventory_message(db: &Connection) -> Result<Vec<u8>, CommonplaceError> {
+    Ok(serde_json::to_vec(&get_inventory(db)?).unwrap())
+}
+
+fn read_inventory(stream: &mut TcpStream) -> Result<Inventory, CommonplaceError> {
+    let inventory = read_message(stream)?;
+    serde_json::from_slice(&inventory).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+fn bundle_path() -> PathBuf {
+    std::env::temp_dir().join(format!("commonplace-sync-{}.bundle", Uuid::new_v4()))
+}
+
+fn bundle_message(db: &Connection, inventory: &Inventory) -> Result<Vec<u8>, CommonplaceError> {
+    let path = bundle_path();
+    let bundle = export_bundle_for(db, &path, inventory).and_then(|()| Ok(fs::read(&path)?));
+    fs::remove_file(&path).ok();
+    bundle
+}
+
+fn import_message(db: &Connection, bundle: Vec<u8>) -> Result<ImportReport, CommonplaceError> {
+    let path = bundle_path();
+    fs::write(&path, bundle)?;
+    let report = import_bundle(db, &path, None);
+    fs::remove_file(&path).ok();
+    report
+}
+
+fn summary(report: &ImportReport) -> String {
+    let mut summary = format!(
+        "added {} notes, updated {}, merged {}, deleted {}; added {} tags and {} blobs",
+        report.notes_added, report.notes_updated, report.notes_merged, report.notes_deleted, report.tags, report.blobs
+    );
+    if report.conflicts > 0 {
+        summary.push_str(&format!("; {} notes have conflicting changes", report.conflicts));
+    }
+    summary
+}
+
+// The lock is held while the repository is read or written, but not while waiting on the network,
+// so a slow client doesn't hold up the others, and two syncs never import at once.
+fn serve_client(repo: &Path, lock: &Mutex<()>, stream: &mut TcpStream) -> Result<String, CommonplaceError> {
+    set_timeouts(stream)?;
+    let db = open_db(repo)?;
+    let theirs = read_inventory(stream)?;
+    let (inventory, bundle) = {
+        let _lock = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        (inventory_message(&db)?, bundle_message(&db, &theirs)?)
+    };
+    write_message(stream, &inventory)?;
+    write_message(stream, &bundle)?;
+    let bundle = read_message(stream)?;
+    let report = {
+        let _lock = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        import_message(&db, bundle)
+    };
+    let summary = match report {
+        Ok(report) => summary(&report),
+        Err(e) => format!("error: {:?}", e),
+    };
+    write_message(stream, summary.as_bytes())?;
+    Ok(summary)
+}
+
+// Each client is served on its own thread.
+fn serve(repo: &Path, address: &str) -> Result<(), CommonplaceError> {
+    let listener = TcpListener::bind(address)?;
+    println!("listening on {}", listener.local_addr()?);
+    io::stdout().flush()?;
+    let lock = Arc::new(Mutex::new(()));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                continue;
+            },
+        };
+        let repo = repo.to_path_buf();
+        let lock = lock.clone();
+        thread::spawn(move || {
+            let client = stream.peer_addr().map(|x| x.to_string()).unwrap_or_default();
+            match serve_client(&repo, &lock, &mut stream) {
+                Ok(summary) => println!("{}: {}", client, summary),
+                Err(e) => eprintln!("{}: {:?}", client, e),
+            }
+        });
+    }
+    Ok(())
+}
+
+fn sync(db: &Connection, address: &str) -> Result<(), CommonplaceError> {
+    let mut stream = TcpStream::connect(address)?;
+    set_timeouts(&stream)?;
+    write_message(&mut stream, &inventory_message(db)?)?;
+    let theirs = read_inventory(&mut stream)?;
+    let bundle = read_message(&mut stream)?;
+    write_message(&mut stream, &bundle_message(db, &theirs)?)?;
+    println!("pulled: {}", summary(&import_message(db, bundle)?));
+    println!("pushed: {}", String::from_utf8_lossy(&read_message(&mut stream)?));
+    Ok(())
+}
+
+fn main() -> Result<(), CommonplaceError> {
+    let args = Args::from_args();
+    let repo = find_repo(args.repo.as_deref())?;
+    match args.cmdline {
+        Cmdline::Serve { address } => serve(&repo, &address),
+        Cmdline::Sync { address } => sync(&open_db(&repo)?, &address),
+    }
+}
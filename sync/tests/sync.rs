@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use uuid::Uuid;
+
+use libcommonplace::{open_db, init_memex, add_note, update_note_bytes, delete_note, get_all_notes, get_note_contents, Connection};
+
+// Syncs two repositories in a temporary directory through a real `commonplace-sync serve`.
+
+struct Scratch {
+    dir: PathBuf,
+    server: Option<Child>,
+}
+
+impl Scratch {
+    fn new() -> Scratch {
+        let dir = std::env::temp_dir().join(format!("commonplace-sync-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        Scratch { dir, server: None }
+    }
+
+    fn repo(&self, name: &str) -> (PathBuf, Connection) {
+        let path = self.dir.join(name);
+        let db = open_db(&path).unwrap();
+        init_memex(&db).unwrap();
+        (path, db)
+    }
+
+    fn add_note(&self, db: &Connection, name: &str, contents: &str) -> Uuid {
+        let path = self.dir.join(name);
+        fs::write(&path, contents).unwrap();
+        add_note(db, name.to_string(), path).unwrap()
+    }
+
+    // Starts serving the repository on a free port, and returns the address it's listening on.
+    fn serve(&mut self, repo: &Path) -> String {
+        let mut server = Command::new(env!("CARGO_BIN_EXE_commonplace-sync"))
+            .arg("--repo").arg(repo)
+            .args(["serve", "--address", "127.0.0.1:0"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(server.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        // The server prints a line for every sync, which it can't do if nothing is reading them.
+        thread::spawn(move || stdout.lines().for_each(drop));
+        self.server = Some(server);
+        line.trim().trim_start_matches("listening on ").to_string()
+    }
+
+    fn sync(&self, repo: &Path, address: &str) {
+        let status = Command::new(env!("CARGO_BIN_EXE_commonplace-sync"))
+            .arg("--repo").arg(repo)
+            .args(["sync", address])
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if let Some(server) = &mut self.server {
+            server.kill().ok();
+            server.wait().ok();
+        }
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+fn contents(db: &Connection) -> HashMap<Uuid, String> {
+    get_all_notes(db).unwrap().keys()
+        .map(|&id| (id, String::from_utf8(get_note_contents(db, id).unwrap()).unwrap()))
+        .collect()
+}
+
+#[test]
+fn repositories_converge() {
+    let mut scratch = Scratch::new();
+    let (server_repo, server) = scratch.repo("server.db");
+    let (client_repo, client) = scratch.repo("client.db");
+    let shared = scratch.add_note(&server, "shared.txt", "one\ntwo\nthree\n");
+    let doomed = scratch.add_note(&server, "doomed.txt", "delete me\n");
+    let address = scratch.serve(&server_repo);
+
+    scratch.sync(&client_repo, &address);
+    assert_eq!(contents(&client), contents(&server));
+
+    // Both sides edit different lines of the same note, and the client deletes the other one.
+    update_note_bytes(&server, shared, b"ONE\ntwo\nthree\n".to_vec()).unwrap();
+    update_note_bytes(&client, shared, b"one\ntwo\nTHREE\n".to_vec()).unwrap();
+    delete_note(&client, doomed).unwrap();
+    scratch.add_note(&client, "new.txt", "from the client\n");
+
+    scratch.sync(&client_repo, &address);
+    let merged = contents(&server);
+    assert_eq!(merged.get(&shared).map(|x| x.as_str()), Some("ONE\ntwo\nTHREE\n"));
+    assert!(!merged.contains_key(&doomed));
+    assert_eq!(merged.len(), 2);
+    assert_eq!(contents(&client), merged);
+}